@@ -1,10 +1,10 @@
-extern crate serde_derive;
 extern crate bincode;
 
 use bincode::{serialize, deserialize};
-use lseq::{Id, Node, NodeId};
+use lseq::{LSeq, NodeId, Op, VersionVector};
 use serde_derive::{Serialize, Deserialize};
 
+use std::fmt;
 use std::net::TcpStream;
 use std::io::{Read, Write, stdin, stdout};
 use std::process::exit;
@@ -13,14 +13,11 @@ use std::thread;
 
 // client handles editing and updating. Each client is an lseq node.
 pub fn run_client(port: String) {
-    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).expect("Could not connect to server");
-
-    // Read our node id.
-    let mut buf = [0u8; 4];
-    assert_eq!(stream.read(&mut buf).expect("Failed to get node id"), 4);
+    let stream = TcpStream::connect(format!("127.0.0.1:{}", port)).expect("Could not connect to server");
 
     let mut client = Client {
-        buffer: Arc::new(Mutex::new(Buffer::new(u32::from_be_bytes(buf)))),
+        buffer: Arc::new(Mutex::new(Buffer::new(NodeId::random()))),
+        writer: Arc::new(Mutex::new(stream.try_clone().expect("could not clone stream"))),
         stream,
     };
     client.run();
@@ -28,41 +25,54 @@ pub fn run_client(port: String) {
 
 struct Client {
     buffer: Arc<Mutex<Buffer>>,
+    // Shared by both threads, so that messages are not interleaved.
+    writer: Arc<Mutex<TcpStream>>,
     stream: TcpStream,
 }
 
+// Messages are broadcast to all clients by the server.
+#[derive(Serialize, Deserialize)]
+enum Message {
+    Ops(Vec<Op<char>>),
+    // Sent on joining and by `.s`, other clients reply with the ops which the
+    // sender has missed.
+    Sync(VersionVector),
+}
+
 struct Buffer {
-    node: Node,
-    internal: Vec<(Id, char)>,
+    seq: LSeq<char>,
 }
 
 impl Client {
     fn run(&mut self) {
+        let version = self.buffer.lock().unwrap().seq.version().clone();
+        send(&self.writer, &Message::Sync(version));
+
         let clone_buf = self.buffer.clone();
-        let stream = self.stream.try_clone().expect("could not clone stream");
+        let writer = self.writer.clone();
         thread::spawn(move || {
-            Client::listen_stdin(clone_buf.clone(), stream);
+            Client::listen_stdin(clone_buf.clone(), writer);
         });
 
         self.listen_server();
     }
 
     // wait for user changes, update the buffer, and send them to the server
-    fn listen_stdin(buf: Arc<Mutex<Buffer>>, mut stream: TcpStream) {
+    fn listen_stdin(buf: Arc<Mutex<Buffer>>, writer: Arc<Mutex<TcpStream>>) {
         loop {
-            print!("{}\n> ", buf.lock().unwrap().to_string());
+            print!("{}\n> ", buf.lock().unwrap());
             stdout().flush().unwrap();
 
             let mut input = String::new();
             stdin().read_line(&mut input).unwrap();
-            let op = if input.starts_with('.') {
+            let msg = if input.starts_with('.') {
                 let mut chars = input.chars();
                 chars.next(); // '.'
                 match chars.next() {
                     Some('i') => {
                         assert_eq!(chars.next(), Some(' '));
                         let mut s = String::new();
-                        while let Some(c) = chars.next() {
+                        for c in chars.by_ref() {
                             if c.is_whitespace() {
                                 break;
                             }
@@ -71,7 +81,7 @@ impl Client {
                         let index = s.parse().unwrap();
 
                         let mut s = String::new();
-                        while let Some(c) = chars.next() {
+                        for c in chars.by_ref() {
                             if c == '\n' {
                                 break;
                             }
@@ -79,12 +89,12 @@ impl Client {
                         }
 
                         let mut buf = buf.lock().unwrap();
-                        buf.insert(index, &s)
+                        Message::Ops(buf.insert(index, &s))
                     }
                     Some('d') => {
                         assert_eq!(chars.next(), Some(' '));
                         let mut s = String::new();
-                        while let Some(c) = chars.next() {
+                        for c in chars.by_ref() {
                             if c.is_whitespace() {
                                 break;
                             }
@@ -94,7 +104,7 @@ impl Client {
                         let index = s.parse().unwrap();
 
                         let mut s = String::new();
-                        while let Some(c) = chars.next() {
+                        for c in chars.by_ref() {
                             if c.is_whitespace() {
                                 break;
                             }
@@ -104,8 +114,9 @@ impl Client {
                         let len = s.parse().unwrap();
 
                         let mut buf = buf.lock().unwrap();
-                        buf.delete(index, len)
+                        Message::Ops(buf.delete(index, len))
                     }
+                    Some('s') => Message::Sync(buf.lock().unwrap().seq.version().clone()),
                     Some('q') => exit(0),
                     c => {
                         println!("unknown command {:?}", c);
@@ -114,12 +125,11 @@ impl Client {
                 }
             } else {
                 let mut buf = buf.lock().unwrap();
-                buf.append(input.trim_right())
+                Message::Ops(buf.append(input.trim_end()))
             };
-            if op.is_some() {
-                let serialised = serialize(&op).expect("Could not serialize Op");
-                stream.write(&(serialised.len() as u32).to_le_bytes()).expect("could not send size to server");
-                stream.write(&serialised).expect("could not send to server");
+            match msg {
+                Message::Ops(ref ops) if ops.is_empty() => {}
+                msg => send(&writer, &msg),
             }
         }
     }
@@ -143,119 +153,70 @@ impl Client {
                 }
             }
 
-            let op: Op = deserialize(&buf).expect("Could not deserialize Op");
+            let msg: Message = deserialize(&buf).expect("Could not deserialize Message");
             let mut buffer = self.buffer.lock().unwrap();
-            buffer.apply(op);
+            match msg {
+                Message::Ops(ops) => buffer.apply(ops),
+                Message::Sync(version) => {
+                    let ops = buffer.seq.missing_ops_since(&version);
+                    if !ops.is_empty() {
+                        send(&self.writer, &Message::Ops(ops));
+                    }
+                }
+            }
 
             buf.clear();
         }
     }
 }
 
+fn send(writer: &Mutex<TcpStream>, msg: &Message) {
+    let serialised = serialize(msg).expect("Could not serialize Message");
+    let mut stream = writer.lock().unwrap();
+    stream.write_all(&(serialised.len() as u32).to_le_bytes()).expect("could not send size to server");
+    stream.write_all(&serialised).expect("could not send to server");
+}
+
 impl Buffer {
-    fn new(node_number: u32) -> Buffer {
+    fn new(node_id: NodeId) -> Buffer {
         Buffer {
-            node: Node::new(NodeId::new(node_number)),
-            internal: Vec::new(),
+            seq: LSeq::new(node_id),
         }
     }
 
-    fn append(&mut self, s: &str) -> Op {
-        let mut prev_id = match self.internal.last() {
-            Some((id, _)) => id.clone(),
-            None => self.node.begin(),
-        };
-        let mut added = Vec::with_capacity(s.len());
-        for c in s.chars() {
-            let id = self.node.new_id_with_bounds(&prev_id, &prev_id);
-            self.internal.push((id.clone(), c));
-            added.push((id.clone(), c));
-            prev_id = id;
-        }
-        Op::Add(added)
+    fn append(&mut self, s: &str) -> Vec<Op<char>> {
+        let position = self.seq.len();
+        self.insert(position, s)
     }
 
-    fn insert(&mut self, position: usize, s: &str) -> Op {
-        if self.internal.len() <= position + 1 {
-            self.append(s);
-            return Op::None;
-        }
-
-        let next_id = self.internal[position].0.clone();
-        let mut prev = position;
-        let begin = self.node.begin();
-        let mut added = Vec::with_capacity(s.len());
-        for c in s.chars() {
-            let prev_id = if prev == 0 {
-                &begin
-            } else {
-                &self.internal[prev - 1].0
-            };
-            let id = self.node.new_id_with_bounds(&prev_id, &next_id);
-            self.internal.insert(prev, (id.clone(), c));
-            added.push((id, c));
-            prev += 1;
-        }
-        Op::Add(added)
+    fn insert(&mut self, position: usize, s: &str) -> Vec<Op<char>> {
+        let position = position.min(self.seq.len());
+        self.seq.insert_many(position, s.chars().collect()).into_iter().collect()
     }
 
-    fn delete(&mut self, position: usize, len: usize) -> Op {
-        let deleted = self.internal.drain(position..position + len);
-        Op::Remove(deleted.map(|(id, _)| id).collect())
+    fn delete(&mut self, position: usize, len: usize) -> Vec<Op<char>> {
+        self.seq.remove_many(position, len).into_iter().collect()
     }
 
-    fn apply(&mut self, op: Op) {
-        let mut changed = false;
-        match op {
-            Op::None => {}
-            Op::Add(id_chars) => {
-                for (id, c) in id_chars {
-                    if id.node == self.node.id {
-                        continue;
-                    }
-
-                    if let Err(i) = self.internal.binary_search_by(|(pid, _)| pid.cmp(&id)) {
-                        self.internal.insert(i, (id, c));
-                        changed = true;
-                    }
-                }
-            }
-            Op::Remove(ids) => {
-                for id in ids {
-                    self.internal.retain(|(i, _)| i != &id);
-                    changed = true;
-                }
+    fn apply(&mut self, ops: Vec<Op<char>>) {
+        let len = self.seq.len();
+        for op in ops {
+            if let Err(e) = self.seq.apply_remote(op) {
+                eprintln!("{}", e);
             }
         }
 
-        if changed {
-            print!("{}\n> ", self.to_string());
+        // Our own ops are echoed back by the server and don't change anything.
+        if self.seq.len() != len {
+            print!("{}\n> ", self);
             stdout().flush().unwrap();
         }
     }
-
-    // FIXME should be a Display impl
-    fn to_string(&self) -> String {
-        let mut result = String::new();
-        self.internal.iter().for_each(|(_, c)| result.push(*c));
-
-        result
-    }
 }
 
-#[derive(Serialize, Deserialize)]
-enum Op {
-    None,
-    Add(Vec<(Id, char)>),
-    Remove(Vec<Id>),
-}
-
-impl Op {
-    fn is_some(&self) -> bool {
-        match self {
-            Op::None => false,
-            _ => true,
-        }
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.seq.iter().try_for_each(|c| write!(f, "{}", c))
     }
 }
 
@@ -264,18 +225,14 @@ mod test {
     use super::*;
 
     fn assert_ordered_ids(buf: &Buffer) {
-        let node = Node::new(NodeId::new(0));
-        let mut prev = &node.begin();
-        eprintln!("{:?}", buf.internal);
-        for (id, _) in &buf.internal {
-            assert!(prev < id);
-            prev = id;
+        for i in 1..buf.seq.len() {
+            assert!(buf.seq.id_at(i - 1) < buf.seq.id_at(i));
         }
     }
 
     #[test]
     fn test_append() {
-        let mut buf = Buffer::new(0);
+        let mut buf = Buffer::new(NodeId::new(0));
         buf.append("Hello");
         buf.append(", world!");
         assert_eq!(&buf.to_string(), "Hello, world!");
@@ -284,7 +241,7 @@ mod test {
 
     #[test]
     fn test_delete() {
-        let mut buf = Buffer::new(0);
+        let mut buf = Buffer::new(NodeId::new(0));
         buf.append("Hello, world!");
         buf.delete(5, 7);
         assert_eq!(&buf.to_string(), "Hello!");
@@ -293,7 +250,7 @@ mod test {
 
     #[test]
     fn test_insert() {
-        let mut buf = Buffer::new(0);
+        let mut buf = Buffer::new(NodeId::new(0));
         buf.append("Hello, world!");
         buf.insert(5, " there");
         assert_eq!(&buf.to_string(), "Hello there, world!");
@@ -302,7 +259,7 @@ mod test {
 
     #[test]
     fn test_insert_begin() {
-        let mut buf = Buffer::new(0);
+        let mut buf = Buffer::new(NodeId::new(0));
         buf.append("Hello, world!");
         buf.delete(0, 1);
         buf.insert(0, "Why h");
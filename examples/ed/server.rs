@@ -3,7 +3,7 @@ use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-// The server assigns node ids and broadcasts messages to all clients.
+// The server broadcasts messages to all clients.
 pub fn run_server() {
     let mut server = Server::new();
     server.handle_requests();
@@ -13,14 +13,12 @@ const PORT: &str = "7878";
 
 struct Server {
     streams: Arc<Mutex<Vec<TcpStream>>>,
-    next_node_id: u32,
 }
 
 impl Server {
     fn new() -> Server {
         Server {
             streams: Arc::new(Mutex::new(Vec::new())),
-            next_node_id: 1,
         }
     }
 
@@ -30,11 +28,8 @@ impl Server {
         println!("Listening on `{}`", addr);
 
         for stream in listener.incoming() {
-            let mut stream = stream.expect("bad stream");
-            // Send the node id.
-            stream.write(&self.next_node_id.to_be_bytes()).expect("could not send node id");
+            let stream = stream.expect("bad stream");
             // Save the stream.
-            self.next_node_id += 1;
             {
                 let mut streams = self.streams.lock().unwrap();
                 streams.push(stream.try_clone().expect("Couldn't clone stream"));
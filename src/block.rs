@@ -0,0 +1,218 @@
+use crate::{Id, Level, LseqError, NodeConfig};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// A run of ids allocated together by `Node::new_block_between`, stored as
+/// the first id, the distance between the last indices of consecutive ids,
+/// and the number of ids.
+///
+/// The ids share all but their last level, and their last levels have the
+/// same node and counter. Since no other id can be allocated with the same
+/// node and counter, a block can be split into smaller blocks (e.g., when an
+/// id is inserted between two of its ids), but never needs to be extended.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct IdBlock {
+    first: Id,
+    step: u64,
+    len: usize,
+}
+
+impl IdBlock {
+    // `first` must not be empty (blocks step its last index), and `step` and
+    // `len` must be non-zero. Callers only pass ids which were allocated or
+    // validated, see `IdBlock::check` for untrusted blocks.
+    pub(crate) fn new(first: Id, step: u64, len: usize) -> IdBlock {
+        debug_assert!(first.depth() > 0 && step > 0 && len > 0);
+        IdBlock { first, step, len }
+    }
+
+    // A block containing only `id`, which must not be empty, as for `new`.
+    pub(crate) fn single(id: Id) -> IdBlock {
+        IdBlock::new(id, 1, 1)
+    }
+
+    /// The number of ids in the block, always at least 1.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn first(&self) -> &Id {
+        &self.first
+    }
+
+    /// The difference between the last indices of consecutive ids.
+    pub(crate) fn step(&self) -> u64 {
+        self.step
+    }
+
+    pub fn last(&self) -> Id {
+        self.id_at(self.len - 1)
+    }
+
+    /// The `i`th id in the block.
+    pub fn get(&self, i: usize) -> Option<Id> {
+        if i < self.len {
+            Some(self.id_at(i))
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Id> + '_ {
+        (0..self.len).map(move |i| self.id_at(i))
+    }
+
+    /// Like `slice::binary_search`, `Ok` with the position of `id` if it is
+    /// in the block, otherwise `Err` with the number of ids in the block which
+    /// are less than `id`.
+    pub fn position(&self, id: &Id) -> Result<usize, usize> {
+        let depth = self.first.depth();
+        // Compare with the prefix shared by all ids in the block.
+        for (level, prefix) in self.first.levels[..depth - 1].iter().enumerate() {
+            match id.levels.get(level).map(|l| l.cmp(prefix)) {
+                // `id` is a prefix of the block's prefix.
+                None => return Err(0),
+                Some(Ordering::Less) => return Err(0),
+                Some(Ordering::Greater) => return Err(self.len),
+                Some(Ordering::Equal) => {}
+            }
+        }
+        let target = match id.levels.get(depth - 1) {
+            Some(target) => target,
+            None => return Err(0),
+        };
+
+        // The number of ids whose last level is less than `target`.
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.level_at(mid) < *target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        if lo < self.len && self.level_at(lo) == *target {
+            if id.depth() == depth {
+                return Ok(lo);
+            }
+            // The id at `lo` is a prefix of `id`, so is less than it.
+            return Err(lo + 1);
+        }
+        Err(lo)
+    }
+
+    /// Check that every id in the block is valid, see `Id::validate`.
+    pub fn validate(&self, config: &NodeConfig) -> Result<(), LseqError> {
+        self.check()?;
+        self.first.validate(config)?;
+        self.last().validate(config)
+    }
+
+    // Check the properties which the other methods rely on, i.e., those which
+    // don't depend on the config.
+    pub(crate) fn check(&self) -> Result<(), LseqError> {
+        if self.len == 0 {
+            return Err(LseqError::InvalidBlock("empty block"));
+        }
+        if self.step == 0 && self.len > 1 {
+            return Err(LseqError::InvalidBlock("zero step"));
+        }
+        let start = self.first.levels.last().ok_or(LseqError::EmptyId)?.index;
+        (self.len as u64 - 1)
+            .checked_mul(self.step)
+            .and_then(|offset| start.checked_add(offset))
+            .ok_or(LseqError::InvalidBlock("index overflow"))?;
+        Ok(())
+    }
+
+    /// Split the block in two, `self` keeps the first `at` ids and the rest
+    /// are returned. `at` must be between 1 and `len - 1`.
+    pub(crate) fn split_off(&mut self, at: usize) -> IdBlock {
+        assert!(at > 0 && at < self.len, "split {} out of bounds (len {})", at, self.len);
+        let rest = IdBlock::new(self.id_at(at), self.step, self.len - at);
+        self.len = at;
+        rest
+    }
+
+    fn level_at(&self, i: usize) -> Level {
+        let last = self.first.levels.last().unwrap();
+        Level {
+            index: last.index + self.step * i as u64,
+            node: last.node,
+            counter: last.counter,
+        }
+    }
+
+    fn id_at(&self, i: usize) -> Id {
+        let mut id = self.first.clone();
+        *id.levels.last_mut().unwrap() = self.level_at(i);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::id;
+
+    #[test]
+    fn test_ids() {
+        let block = IdBlock::new(id(&[(3, 1, 1), (4, 2, 5)]), 3, 4);
+        assert_eq!(block.len(), 4);
+        assert_eq!(block.first(), &id(&[(3, 1, 1), (4, 2, 5)]));
+        assert_eq!(block.get(2), Some(id(&[(3, 1, 1), (10, 2, 5)])));
+        assert_eq!(block.last(), id(&[(3, 1, 1), (13, 2, 5)]));
+        assert_eq!(block.get(4), None);
+        let ids: Vec<_> = block.iter().collect();
+        assert_eq!(ids.len(), 4);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_position() {
+        let block = IdBlock::new(id(&[(3, 1, 1), (4, 2, 5)]), 3, 4);
+        for (i, id) in block.iter().enumerate() {
+            assert_eq!(block.position(&id), Ok(i));
+        }
+
+        assert_eq!(block.position(&id(&[(3, 1, 1)])), Err(0));
+        assert_eq!(block.position(&id(&[(2, 1, 1), (9, 9, 9)])), Err(0));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (4, 2, 4)])), Err(0));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (4, 2, 5), (1, 3, 1)])), Err(1));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (5, 0, 0)])), Err(1));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (7, 1, 9)])), Err(1));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (7, 2, 6)])), Err(2));
+        assert_eq!(block.position(&id(&[(3, 1, 1), (14, 0, 0)])), Err(4));
+        assert_eq!(block.position(&id(&[(3, 1, 2), (1, 0, 0)])), Err(4));
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut block = IdBlock::new(id(&[(4, 2, 5)]), 2, 5);
+        let rest = block.split_off(2);
+        assert_eq!(block.iter().chain(rest.iter()).collect::<Vec<_>>(), IdBlock::new(id(&[(4, 2, 5)]), 2, 5).iter().collect::<Vec<_>>());
+        assert_eq!(block.len(), 2);
+        assert_eq!(rest.first(), &id(&[(8, 2, 5)]));
+        assert_eq!(rest.len(), 3);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = NodeConfig::new();
+        assert_eq!(IdBlock::new(id(&[(1, 2, 5)]), 2, 7).validate(&config), Ok(()));
+        assert_eq!(IdBlock::single(id(&[(15, 2, 5)])).validate(&config), Ok(()));
+        assert_eq!(
+            IdBlock::new(id(&[(1, 2, 5)]), 2, 9).validate(&config),
+            Err(LseqError::IndexOutOfWidth { level: 0, index: 17, width: 16 })
+        );
+        let invalid = IdBlock { first: id(&[(1, 2, 5)]), step: 0, len: 2 };
+        assert_eq!(invalid.validate(&config), Err(LseqError::InvalidBlock("zero step")));
+        let invalid = IdBlock { first: id(&[(1, 2, 5)]), step: 1, len: 0 };
+        assert_eq!(invalid.validate(&config), Err(LseqError::InvalidBlock("empty block")));
+        let invalid = IdBlock { first: id(&[(1, 2, 5)]), step: u64::MAX, len: 3 };
+        assert_eq!(invalid.validate(&config), Err(LseqError::InvalidBlock("index overflow")));
+    }
+}
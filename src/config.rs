@@ -0,0 +1,336 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+const INITIAL_WIDTH: u64 = 16;
+const DEFAULT_BOUNDARY: u64 = 10;
+const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Parameters for allocating ids. All replicas of a sequence must use the same
+/// config, compare `NodeConfig::fingerprint`s to check.
+///
+/// ```
+/// use lseq::{NodeConfig, WidthGrowth};
+///
+/// let config = NodeConfig::builder()
+///     .initial_width(32)
+///     .boundary(4)
+///     .growth(WidthGrowth::Constant)
+///     .max_depth(8)
+///     .build();
+/// assert_eq!(config.width_at(3), 32);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeConfig {
+    initial_width: u64,
+    boundary: u64,
+    growth: WidthGrowth,
+    max_depth: usize,
+    document_seed: Option<u64>,
+}
+
+/// How the width of each level of the id tree relates to the width of the
+/// level above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WidthGrowth {
+    /// Every level has the initial width.
+    Constant,
+    /// Each level is twice as wide as the one above (the LSEQ default).
+    Doubling,
+    /// Each level is wider than the one above by the given amount.
+    Linear(u64),
+}
+
+impl NodeConfig {
+    pub fn new() -> NodeConfig {
+        NodeConfig {
+            initial_width: INITIAL_WIDTH,
+            boundary: DEFAULT_BOUNDARY,
+            growth: WidthGrowth::Doubling,
+            max_depth: DEFAULT_MAX_DEPTH,
+            document_seed: None,
+        }
+    }
+
+    /// Start building a config from the default values.
+    pub fn builder() -> NodeConfigBuilder {
+        NodeConfigBuilder { config: NodeConfig::new() }
+    }
+
+    pub fn initial_width(&self) -> u64 {
+        self.initial_width
+    }
+
+    pub fn boundary(&self) -> u64 {
+        self.boundary
+    }
+
+    pub fn growth(&self) -> WidthGrowth {
+        self.growth
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn document_seed(&self) -> Option<u64> {
+        self.document_seed
+    }
+
+    /// The largest width of any level. Once the growth function reaches this
+    /// width, deeper levels all have this width, so the width of a level
+    /// never overflows however deep ids get.
+    pub const MAX_WIDTH: u64 = 1 << 48;
+
+    /// The number of indices available at `level`, at most `MAX_WIDTH`.
+    pub fn width_at(&self, level: usize) -> u64 {
+        let width = match self.growth {
+            WidthGrowth::Constant => Some(self.initial_width),
+            WidthGrowth::Doubling => u32::try_from(level)
+                .ok()
+                .and_then(|level| 2_u64.checked_pow(level))
+                .and_then(|factor| self.initial_width.checked_mul(factor)),
+            WidthGrowth::Linear(step) => step
+                .checked_mul(level as u64)
+                .and_then(|extra| self.initial_width.checked_add(extra)),
+        };
+        width.map_or(NodeConfig::MAX_WIDTH, |w| w.min(NodeConfig::MAX_WIDTH))
+    }
+
+    /// A hash of the config which is stable across platforms and versions of
+    /// Rust. Replicas with different fingerprints will generate incompatible
+    /// ids.
+    pub fn fingerprint(&self) -> u64 {
+        let (growth, step) = match self.growth {
+            WidthGrowth::Constant => (0, 0),
+            WidthGrowth::Doubling => (1, 0),
+            WidthGrowth::Linear(step) => (2, step),
+        };
+
+        let mut hash = Fnv::new();
+        hash.write_u64(self.initial_width);
+        hash.write_u64(self.boundary);
+        hash.write_u64(growth);
+        hash.write_u64(step);
+        hash.write_u64(self.max_depth as u64);
+        // Only hashed if present so that adding the seed did not change the
+        // fingerprint of existing configs.
+        if let Some(seed) = self.document_seed {
+            hash.write_u64(seed);
+        }
+        hash.0
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig::new()
+    }
+}
+
+/// Builds a `NodeConfig`, see `NodeConfig::builder`.
+#[derive(Debug, Clone)]
+pub struct NodeConfigBuilder {
+    config: NodeConfig,
+}
+
+impl NodeConfigBuilder {
+    /// The width of the top level of the id tree. Must be at least 2 and at
+    /// most `NodeConfig::MAX_WIDTH`.
+    pub fn initial_width(mut self, initial_width: u64) -> NodeConfigBuilder {
+        self.config.initial_width = initial_width;
+        self
+    }
+
+    /// The maximum distance from the bound used when picking an index. Must
+    /// be at least 1.
+    pub fn boundary(mut self, boundary: u64) -> NodeConfigBuilder {
+        self.config.boundary = boundary;
+        self
+    }
+
+    pub fn growth(mut self, growth: WidthGrowth) -> NodeConfigBuilder {
+        self.config.growth = growth;
+        self
+    }
+
+    /// The maximum number of levels in an id. Must be at least 1.
+    pub fn max_depth(mut self, max_depth: usize) -> NodeConfigBuilder {
+        self.config.max_depth = max_depth;
+        self
+    }
+
+    /// A seed shared by all replicas of a document. If set, the allocation
+    /// strategies choose boundary+ or boundary- for each level as a function
+    /// of this seed, rather than randomly per replica, so that replicas
+    /// allocate consistently.
+    pub fn document_seed(mut self, seed: u64) -> NodeConfigBuilder {
+        self.config.document_seed = Some(seed);
+        self
+    }
+
+    /// Panics if any of the parameters are out of range.
+    pub fn build(self) -> NodeConfig {
+        if let Err(msg) = self.config.check() {
+            panic!("{}", msg);
+        }
+        self.config
+    }
+}
+
+impl NodeConfig {
+    fn check(&self) -> Result<(), String> {
+        if self.initial_width < 2 {
+            return Err(format!("initial width must be at least 2, found {}", self.initial_width));
+        }
+        if self.initial_width > NodeConfig::MAX_WIDTH {
+            return Err(format!(
+                "initial width must be at most {}, found {}",
+                NodeConfig::MAX_WIDTH,
+                self.initial_width
+            ));
+        }
+        if self.boundary < 1 {
+            return Err("boundary must be at least 1".to_owned());
+        }
+        if self.max_depth < 1 {
+            return Err("max depth must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// Checks the parameters, like `NodeConfigBuilder::build`.
+impl<'de> Deserialize<'de> for NodeConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NodeConfig, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "NodeConfig")]
+        struct Fields {
+            initial_width: u64,
+            boundary: u64,
+            growth: WidthGrowth,
+            max_depth: usize,
+            document_seed: Option<u64>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        let config = NodeConfig {
+            initial_width: f.initial_width,
+            boundary: f.boundary,
+            growth: f.growth,
+            max_depth: f.max_depth,
+            document_seed: f.document_seed,
+        };
+        config.check().map_err(de::Error::custom)?;
+        Ok(config)
+    }
+}
+
+// 64 bit FNV-1a. We can't use `DefaultHasher` since its output may change
+// between releases.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        for b in &n.to_le_bytes() {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_width_at() {
+        let builder = NodeConfig::builder().initial_width(5);
+        let config = builder.clone().build();
+        assert_eq!(config.width_at(0), 5);
+        assert_eq!(config.width_at(4), 80);
+
+        let config = builder.clone().growth(WidthGrowth::Constant).build();
+        assert_eq!(config.width_at(0), 5);
+        assert_eq!(config.width_at(4), 5);
+
+        let config = builder.growth(WidthGrowth::Linear(3)).build();
+        assert_eq!(config.width_at(0), 5);
+        assert_eq!(config.width_at(4), 17);
+    }
+
+    #[test]
+    fn test_width_at_deep() {
+        let max = NodeConfig::MAX_WIDTH;
+        let config = NodeConfig::new();
+        assert_eq!(config.width_at(31), 16 << 31);
+        assert_eq!(config.width_at(43), max / 2);
+        for level in 44..200 {
+            assert_eq!(config.width_at(level), max);
+        }
+        assert_eq!(config.width_at(usize::MAX), max);
+
+        let config = NodeConfig::builder().growth(WidthGrowth::Linear(u64::MAX / 2)).build();
+        assert_eq!(config.width_at(0), 16);
+        assert_eq!(config.width_at(1), max);
+        assert_eq!(config.width_at(usize::MAX), max);
+
+        let config = NodeConfig::builder().initial_width(max).build();
+        assert_eq!(config.width_at(0), max);
+        assert_eq!(config.width_at(100), max);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let a = NodeConfig::new();
+        assert_eq!(a.fingerprint(), NodeConfig::default().fingerprint());
+        assert_eq!(a.fingerprint(), NodeConfig::builder().build().fingerprint());
+        // Fingerprints are exchanged between replicas, so must not change.
+        assert_eq!(a.fingerprint(), 0x5a0d_9d59_606b_5cde);
+
+        let configs = [
+            NodeConfig::builder().initial_width(32).build(),
+            NodeConfig::builder().boundary(4).build(),
+            NodeConfig::builder().growth(WidthGrowth::Constant).build(),
+            NodeConfig::builder().growth(WidthGrowth::Linear(1)).build(),
+            NodeConfig::builder().growth(WidthGrowth::Linear(2)).build(),
+            NodeConfig::builder().max_depth(8).build(),
+            NodeConfig::builder().document_seed(0).build(),
+            NodeConfig::builder().document_seed(1).build(),
+        ];
+        for (i, c) in configs.iter().enumerate() {
+            assert_ne!(c.fingerprint(), a.fingerprint());
+            for c2 in &configs[i + 1..] {
+                assert_ne!(c.fingerprint(), c2.fingerprint());
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_initial_width_too_small() {
+        NodeConfig::builder().initial_width(1).build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_initial_width_too_large() {
+        NodeConfig::builder().initial_width(NodeConfig::MAX_WIDTH + 1).build();
+    }
+
+    #[test]
+    fn test_serde() {
+        let config = NodeConfig::builder().growth(WidthGrowth::Linear(3)).document_seed(7).build();
+        let bytes = bincode::serialize(&config).unwrap();
+        assert_eq!(bincode::deserialize::<NodeConfig>(&bytes).unwrap(), config);
+
+        let mut invalid = NodeConfig::new();
+        invalid.initial_width = 1;
+        let bytes = bincode::serialize(&invalid).unwrap();
+        assert!(bincode::deserialize::<NodeConfig>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,561 @@
+//! The elements and removed ids of an `LSeq` or a `Delta`.
+//!
+//! Each run of elements and each removed id records the op (its `Dot`) which
+//! inserted or removed it, so that the changes since a `VersionVector` can be
+//! found without keeping every op.
+
+use crate::tree::{OrderTree, Weighted};
+use crate::version::Dot;
+use crate::{Id, IdBlock, LseqError, NodeConfig, Rebalance, VersionVector};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+
+#[derive(Clone)]
+pub(crate) struct Contents<T> {
+    // Keyed by the first id of each run.
+    elements: OrderTree<Id, Run<T>>,
+    // The ids of all removed elements, and the op which removed them (the
+    // least, if several did).
+    removed: BTreeMap<Id, Dot>,
+}
+
+// Elements with consecutive ids from a block, `values[i]` has id `ids.get(i)`.
+#[derive(Clone)]
+struct Run<T> {
+    ids: IdBlock,
+    // The op which inserted the elements.
+    dot: Dot,
+    values: Vec<T>,
+}
+
+impl<T> Weighted for Run<T> {
+    fn weight(&self) -> usize {
+        self.values.len()
+    }
+}
+
+// Where an id is, or would be, in the elements.
+enum Place {
+    Present,
+    // Not present, `next` is the least id greater than the id. If `split` is
+    // `Some`, the id is between two elements of the run with the given key,
+    // the run must be split at the given offset before inserting the id.
+    Absent { next: Option<Id>, split: Option<(Id, usize)> },
+}
+
+/// The serialized form of `Contents`, which must be checked with
+/// `Contents::from_fields`.
+#[derive(Deserialize)]
+#[serde(rename = "Contents")]
+pub(crate) struct ContentsFields<T> {
+    runs: Vec<(IdBlock, Dot, Vec<T>)>,
+    removed: Vec<(Id, Dot)>,
+}
+
+impl<T> Contents<T> {
+    pub(crate) fn new() -> Contents<T> {
+        Contents {
+            elements: OrderTree::new(),
+            removed: BTreeMap::new(),
+        }
+    }
+
+    /// Check that the ids and number of values of `fields` are consistent,
+    /// but not that the ids are valid for any particular config, see
+    /// `validate`.
+    pub(crate) fn from_fields(fields: ContentsFields<T>) -> Result<Contents<T>, LseqError> {
+        let mut contents = Contents::new();
+        let mut last: Option<Id> = None;
+        for (ids, dot, values) in fields.runs {
+            ids.check()?;
+            if ids.len() != values.len() {
+                return Err(LseqError::InvalidBlock("wrong number of values"));
+            }
+            if last.is_some_and(|last| last >= *ids.first()) {
+                return Err(LseqError::InvalidState("elements out of order"));
+            }
+            last = Some(ids.last());
+            contents.elements.insert(ids.first().clone(), Run { ids, dot, values });
+        }
+        for (id, dot) in fields.removed {
+            if contents.index_of(&id).is_some() {
+                return Err(LseqError::InvalidState("removed element is present"));
+            }
+            contents.removed.insert(id, dot);
+        }
+        Ok(contents)
+    }
+
+    /// Check that every id is valid, see `Id::validate`.
+    pub(crate) fn validate(&self, config: &NodeConfig) -> Result<(), LseqError> {
+        for (_, run) in self.elements.iter() {
+            run.ids.validate(config)?;
+        }
+        self.removed.keys().try_for_each(|id| id.validate(config))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Whether there are no elements and no removed ids.
+    pub(crate) fn is_blank(&self) -> bool {
+        self.is_empty() && self.removed.is_empty()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<&T> {
+        let (_, run, offset) = self.elements.get_index(index)?;
+        run.values.get(offset)
+    }
+
+    pub(crate) fn get_by_id(&self, id: &Id) -> Option<&T> {
+        let (_, run, _) = self.elements.floor(id)?;
+        run.values.get(run.ids.position(id).ok()?)
+    }
+
+    pub(crate) fn id_at(&self, index: usize) -> Option<Id> {
+        let (_, run, offset) = self.elements.get_index(index)?;
+        run.ids.get(offset)
+    }
+
+    pub(crate) fn index_of(&self, id: &Id) -> Option<usize> {
+        let (_, run, start) = self.elements.floor(id)?;
+        Some(start + run.ids.position(id).ok()?)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter().flat_map(|(_, run)| &run.values)
+    }
+
+    /// The elements with their ids, in order.
+    pub(crate) fn elements(&self) -> impl Iterator<Item = (Id, &T)> {
+        self.elements.iter().flat_map(|(_, run)| run.ids.iter().zip(&run.values))
+    }
+
+    /// The number of runs the elements are stored in.
+    #[cfg(test)]
+    pub(crate) fn run_count(&self) -> usize {
+        self.elements.iter().count()
+    }
+
+    /// Insert the ids which are not already present and have not been
+    /// removed, recording that they were inserted by `dot`.
+    pub(crate) fn insert_block(&mut self, ids: IdBlock, values: Vec<T>, dot: Dot) {
+        debug_assert_eq!(ids.len(), values.len());
+        // Other elements may be between ids of the block (e.g., if they were
+        // inserted concurrently with it), so the block is split into runs
+        // which fit between existing and removed elements.
+        let mut rest = Some(Run { ids, dot, values });
+        while let Some(mut run) = rest.take() {
+            // Removed ids are skipped like present ones.
+            let place = match self.place(run.ids.first()) {
+                Place::Absent { .. } if self.removed.contains_key(run.ids.first()) => Place::Present,
+                place => place,
+            };
+
+            // The number of ids in `run` which can be inserted before reaching
+            // an existing or removed element.
+            let fits = match place {
+                Place::Present => {
+                    if run.ids.len() > 1 {
+                        rest = Some(run.split_off(1));
+                    }
+                    continue;
+                }
+                Place::Absent { next, split } => {
+                    if let Some((key, offset)) = split {
+                        self.split_run(&key, offset);
+                    }
+                    let removed = self.removed.range(run.ids.first()..).next().map(|(id, _)| id);
+                    next.iter()
+                        .chain(removed)
+                        .map(|id| run.ids.position(id).unwrap_or_else(|i| i))
+                        .min()
+                        .unwrap_or_else(|| run.ids.len())
+                }
+            };
+
+            if fits < run.ids.len() {
+                rest = Some(run.split_off(fits));
+            }
+            self.elements.insert(run.ids.first().clone(), run);
+        }
+    }
+
+    /// Remove `id`, whether or not it is present, recording that it was
+    /// removed by `dot`.
+    pub(crate) fn remove_id(&mut self, id: &Id, dot: Dot) -> Option<T> {
+        let removed_by = self.removed.entry(id.clone()).or_insert(dot);
+        *removed_by = (*removed_by).min(dot);
+
+        let (key, offset) = {
+            let (key, run, _) = self.elements.floor(id)?;
+            (key.clone(), run.ids.position(id).ok()?)
+        };
+
+        let mut run = self.elements.remove(&key).unwrap();
+        if offset + 1 < run.ids.len() {
+            let after = run.split_off(offset + 1);
+            self.elements.insert(after.ids.first().clone(), after);
+        }
+        if offset > 0 {
+            let removed = run.split_off(offset);
+            self.elements.insert(key, run);
+            removed.values.into_iter().next()
+        } else {
+            run.values.into_iter().next()
+        }
+    }
+
+    /// The ids of the `len` elements starting at `index`, as blocks.
+    pub(crate) fn blocks(&self, index: usize, len: usize) -> Vec<IdBlock> {
+        let mut blocks = Vec::new();
+        let mut remaining = len;
+        let mut next = self.elements.get_index(index);
+        while let Some((key, run, offset)) = next.filter(|_| remaining > 0) {
+            let mut ids = run.ids.clone();
+            if offset > 0 {
+                ids = ids.split_off(offset);
+            }
+            if remaining < ids.len() {
+                ids.split_off(remaining);
+            }
+            remaining -= ids.len();
+            blocks.push(ids);
+            next = self.elements.next(key).map(|(key, run)| (key, run, 0));
+        }
+        blocks
+    }
+
+    /// Rename the elements and removed ids in the rebalanced range, see
+    /// `Rebalance::translate`. Returns the dropped elements and removed ids,
+    /// with their old ids.
+    pub(crate) fn rebalance(&mut self, rebalance: &Rebalance, config: &NodeConfig) -> Contents<T> {
+        self.rename_range(rebalance, |ids| rebalance.translate_block(ids, config), |id| rebalance.translate(id, config))
+    }
+
+    /// Undo `rebalance`, see `Rebalance::untranslate`.
+    pub(crate) fn unrebalance(&mut self, rebalance: &Rebalance, config: &NodeConfig) {
+        let dropped = self.rename_range(rebalance, |ids| rebalance.untranslate_block(ids, config), |id| Some(rebalance.untranslate(id, config)));
+        debug_assert!(dropped.is_blank());
+    }
+
+    // Rename the elements and removed ids strictly between the bounds of
+    // `rebalance`, returning the dropped ones. `rename_block` gives the new
+    // ids of a block with the offset of the first id of each, like
+    // `Rebalance::translate_block`.
+    fn rename_range<B, I>(&mut self, rebalance: &Rebalance, rename_block: B, rename: I) -> Contents<T>
+    where
+        B: Fn(&IdBlock) -> Vec<(usize, IdBlock)>,
+        I: Fn(&Id) -> Option<Id>,
+    {
+        let (lower, upper) = rebalance.bounds();
+        let runs = self.take_range(lower, upper);
+        let bound = |id: Option<&Id>| id.map_or(Bound::Unbounded, |id| Bound::Excluded(id.clone()));
+        let removed: Vec<(Id, Dot)> =
+            self.removed.range((bound(lower), bound(upper))).map(|(id, dot)| (id.clone(), *dot)).collect();
+
+        let mut dropped = Contents::new();
+        for (id, _) in &removed {
+            self.removed.remove(id);
+        }
+        for run in runs {
+            // The values of dropped ids are left behind.
+            let mut values: Vec<Option<T>> = run.values.into_iter().map(Some).collect();
+            for (offset, ids) in rename_block(&run.ids) {
+                let kept = values[offset..offset + ids.len()].iter_mut().map(|value| value.take().unwrap()).collect();
+                self.insert_block(ids, kept, run.dot);
+            }
+            for (i, value) in values.into_iter().enumerate() {
+                if let Some(value) = value {
+                    dropped.insert_block(IdBlock::single(run.ids.get(i).unwrap()), vec![value], run.dot);
+                }
+            }
+        }
+        for (id, dot) in removed {
+            match rename(&id) {
+                Some(id) => self.remove_id(&id, dot),
+                None => dropped.remove_id(&id, dot),
+            };
+        }
+        dropped
+    }
+
+    // Remove the runs of elements strictly between `lower` and `upper`,
+    // splitting runs which cross them.
+    fn take_range(&mut self, lower: Option<&Id>, upper: Option<&Id>) -> Vec<Run<T>> {
+        // The index of the first element greater than `lower`.
+        let start = lower
+            .and_then(|lower| {
+                let (_, run, start) = self.elements.floor(lower)?;
+                Some(start + run.ids.position(lower).map_or_else(|i| i, |i| i + 1))
+            })
+            .unwrap_or(0);
+
+        let mut runs = Vec::new();
+        while let Some((key, offset)) = self.elements.get_index(start).map(|(key, _, offset)| (key.clone(), offset)) {
+            if offset > 0 {
+                self.split_run(&key, offset);
+                continue;
+            }
+            if upper.is_some_and(|upper| key >= *upper) {
+                break;
+            }
+            let mut run = self.elements.remove(&key).unwrap();
+            if let Some(end) = upper.map(|upper| run.ids.position(upper).unwrap_or_else(|i| i)) {
+                if end < run.ids.len() {
+                    let rest = run.split_off(end);
+                    self.elements.insert(rest.ids.first().clone(), rest);
+                }
+            }
+            runs.push(run);
+        }
+        runs
+    }
+
+    fn place(&self, id: &Id) -> Place {
+        let (key, run) = match self.elements.floor(id) {
+            Some((key, run, _)) => (key, run),
+            None => {
+                let next = self.elements.iter().next().map(|(key, _)| key.clone());
+                return Place::Absent { next, split: None };
+            }
+        };
+
+        match run.ids.position(id) {
+            Ok(_) => Place::Present,
+            Err(offset) if offset < run.ids.len() => Place::Absent {
+                next: run.ids.get(offset),
+                split: Some((key.clone(), offset)),
+            },
+            Err(_) => Place::Absent {
+                next: self.elements.next(key).map(|(key, _)| key.clone()),
+                split: None,
+            },
+        }
+    }
+
+    // Split the run with key `key` in two at `offset`.
+    fn split_run(&mut self, key: &Id, offset: usize) {
+        let mut run = self.elements.remove(key).unwrap();
+        let rest = run.split_off(offset);
+        self.elements.insert(key.clone(), run);
+        self.elements.insert(rest.ids.first().clone(), rest);
+    }
+}
+
+impl<T: Clone> Contents<T> {
+    /// Add the elements and removed ids of `other`, so that this contains
+    /// the elements present in either and removed in neither.
+    pub(crate) fn join(&mut self, other: &Contents<T>) {
+        for (id, dot) in &other.removed {
+            self.remove_id(id, *dot);
+        }
+        for (_, run) in other.elements.iter() {
+            self.insert_block(run.ids.clone(), run.values.clone(), run.dot);
+        }
+    }
+
+    /// The elements inserted, and ids removed, by ops which are not in
+    /// `version`.
+    pub(crate) fn since(&self, version: &VersionVector) -> Contents<T> {
+        let mut result = Contents::new();
+        for (key, run) in self.elements.iter() {
+            if !version.contains(run.dot.0, run.dot.1) {
+                result.elements.insert(key.clone(), run.clone());
+            }
+        }
+        for (id, dot) in &self.removed {
+            if !version.contains(dot.0, dot.1) {
+                result.removed.insert(id.clone(), *dot);
+            }
+        }
+        result
+    }
+}
+
+/// Equal if the elements and removed ids are equal, however the elements are
+/// split into runs.
+impl<T: PartialEq> PartialEq for Contents<T> {
+    fn eq(&self, other: &Contents<T>) -> bool {
+        self.len() == other.len() && self.elements().eq(other.elements()) && self.removed == other.removed
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Contents<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Contents")
+            .field("elements", &self.elements().collect::<Vec<_>>())
+            .field("removed", &self.removed)
+            .finish()
+    }
+}
+
+impl<T: Serialize> Serialize for Contents<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(rename = "Contents")]
+        struct Fields<'a, T> {
+            runs: Vec<(&'a IdBlock, Dot, &'a [T])>,
+            removed: Vec<(&'a Id, Dot)>,
+        }
+
+        Fields {
+            runs: self.elements.iter().map(|(_, run)| (&run.ids, run.dot, &run.values[..])).collect(),
+            removed: self.removed.iter().map(|(id, dot)| (id, *dot)).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<T> Run<T> {
+    // See `IdBlock::split_off`.
+    fn split_off(&mut self, at: usize) -> Run<T> {
+        Run {
+            ids: self.ids.split_off(at),
+            dot: self.dot,
+            values: self.values.split_off(at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node, NodeId};
+
+    fn dot(seq: u64) -> Dot {
+        (NodeId::new(1), seq)
+    }
+
+    fn block(node: &mut Node, n: usize) -> IdBlock {
+        node.new_block_between(None, None, n)
+    }
+
+    fn assert_runs<T>(contents: &Contents<T>) {
+        let mut ids = Vec::new();
+        for (key, run) in contents.elements.iter() {
+            assert_eq!(key, run.ids.first());
+            assert_eq!(run.ids.len(), run.values.len());
+            ids.extend(run.ids.iter());
+        }
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| !contents.removed.contains_key(id)));
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut node = Node::new(NodeId::new(1));
+        let ids = block(&mut node, 5);
+        let mut contents = Contents::new();
+        contents.insert_block(ids.clone(), vec![0, 1, 2, 3, 4], dot(1));
+        assert_eq!(contents.run_count(), 1);
+
+        assert_eq!(contents.remove_id(&ids.get(2).unwrap(), dot(2)), Some(2));
+        assert_eq!(contents.remove_id(&ids.get(2).unwrap(), dot(3)), None);
+        assert_eq!(contents.removed[&ids.get(2).unwrap()], dot(2));
+        assert_eq!(contents.run_count(), 2);
+        assert_runs(&contents);
+
+        // Re-inserting the block only fills in missing ids which were never
+        // removed.
+        contents.remove_id(&ids.get(4).unwrap(), dot(4));
+        contents.insert_block(ids.clone(), vec![0, 1, 2, 3, 4], dot(1));
+        assert_eq!(contents.iter().cloned().collect::<Vec<_>>(), vec![0, 1, 3]);
+        assert_runs(&contents);
+    }
+
+    #[test]
+    fn test_join_since() {
+        let mut node = Node::new(NodeId::new(1));
+        let ids = block(&mut node, 6);
+        let mut a = Contents::new();
+        a.insert_block(ids.clone(), vec!['a'; 6], dot(1));
+        let mut b = a.clone();
+        a.remove_id(&ids.get(1).unwrap(), dot(2));
+        b.remove_id(&ids.get(4).unwrap(), (NodeId::new(2), 1));
+
+        let mut ab = a.clone();
+        ab.join(&b);
+        let mut ba = b.clone();
+        ba.join(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.len(), 4);
+        assert_runs(&ab);
+
+        let mut version = VersionVector::new();
+        version.observe(NodeId::new(1), 1);
+        let delta = a.since(&version);
+        assert_eq!(delta.len(), 0);
+        assert_eq!(delta.removed.len(), 1);
+        b.join(&delta);
+        assert_eq!(b, ab);
+    }
+
+    #[test]
+    fn test_unrebalance() {
+        let config = NodeConfig::new();
+        let mut node = Node::new(NodeId::new(1));
+        let ids = block(&mut node, 4);
+        let mut contents = Contents::new();
+        contents.insert_block(ids.clone(), vec![0, 1, 2, 3], dot(1));
+        contents.remove_id(&ids.get(3).unwrap(), dot(2));
+        let new = block(&mut node, 4);
+        let rebalance = Rebalance::new(0, None, None, None, contents.blocks(0, 3), new.clone());
+        // Inserted concurrently with the rebalance.
+        let between = node.new_id_between(ids.get(1).as_ref(), ids.get(2).as_ref());
+        contents.insert_block(IdBlock::single(between), vec![9], dot(3));
+        let original = contents.clone();
+
+        assert!(contents.rebalance(&rebalance, &config).is_blank());
+        assert_eq!(contents.iter().cloned().collect::<Vec<_>>(), vec![0, 1, 9, 2]);
+        assert_eq!(contents.id_at(0), new.get(1));
+        contents.unrebalance(&rebalance, &config);
+        assert_eq!(contents, original);
+        assert_runs(&contents);
+
+        // Undoing restores dropped ids with the contents returned by
+        // `rebalance`.
+        let shallow = NodeConfig::builder().max_depth(new.first().depth()).build();
+        let dropped = contents.rebalance(&rebalance, &shallow);
+        assert_eq!(contents.iter().cloned().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped.removed.len(), 1);
+        contents.unrebalance(&rebalance, &shallow);
+        contents.join(&dropped);
+        assert_eq!(contents, original);
+        assert_runs(&contents);
+    }
+
+    #[test]
+    fn test_from_fields() {
+        let mut node = Node::new(NodeId::new(1));
+        let ids = block(&mut node, 3);
+        let fields = |runs, removed| ContentsFields { runs, removed };
+
+        let contents = Contents::from_fields(fields(vec![(ids.clone(), dot(1), vec![1, 2, 3])], vec![])).unwrap();
+        assert_eq!(contents.len(), 3);
+
+        assert_eq!(
+            Contents::from_fields(fields(vec![(ids.clone(), dot(1), vec![1, 2])], vec![])).unwrap_err(),
+            LseqError::InvalidBlock("wrong number of values")
+        );
+        let twice = vec![(ids.clone(), dot(1), vec![1, 2, 3]), (ids.clone(), dot(1), vec![1, 2, 3])];
+        assert_eq!(
+            Contents::from_fields(fields(twice, vec![])).unwrap_err(),
+            LseqError::InvalidState("elements out of order")
+        );
+        let removed = vec![(ids.get(1).unwrap(), dot(2))];
+        assert_eq!(
+            Contents::from_fields(fields(vec![(ids, dot(1), vec![1, 2, 3])], removed)).unwrap_err(),
+            LseqError::InvalidState("removed element is present")
+        );
+    }
+}
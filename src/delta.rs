@@ -0,0 +1,150 @@
+use crate::contents::{Contents, ContentsFields};
+use crate::version::{Dot, Dots};
+use crate::{LseqError, Rebalance};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+
+/// The changes to an `LSeq` made by the ops which some replica has not seen,
+/// created by `LSeq::delta_since` and applied with `LSeq::apply_delta`.
+///
+/// A delta contains the elements inserted, and the ids removed, by those
+/// ops, rather than the ops themselves, so is usually smaller than the ops
+/// (e.g., an element which was inserted then removed is only sent as a
+/// removed id). Deltas can be applied in any order and any number of times,
+/// and several deltas can be combined into one with `Delta::join`.
+///
+/// A delta also contains the rebalances among those ops (see
+/// `LSeq::rebalance`), including any which lost to a concurrent rebalance,
+/// and the elements and removed ids which the replica's rebalances dropped.
+/// Its ids are from the epoch of the replica which created it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta<T> {
+    // The `NodeConfig::fingerprint` of the replica which created the delta.
+    pub(crate) config: u64,
+    // The epoch of the ids in `contents`, and the rebalance which started
+    // it.
+    pub(crate) epoch: u64,
+    pub(crate) base: Option<Dot>,
+    // See `Rebalance::sort`.
+    pub(crate) rebalances: Vec<(Dot, Rebalance)>,
+    // The elements and removed ids dropped by each rebalance, with the ids
+    // from before it.
+    pub(crate) dropped: Vec<(Dot, Contents<T>)>,
+    // The ops whose changes are included.
+    pub(crate) dots: Dots,
+    pub(crate) contents: Contents<T>,
+}
+
+impl<T: Clone> Delta<T> {
+    /// Whether the delta includes no ops, in which case applying it does
+    /// nothing.
+    pub fn is_empty(&self) -> bool {
+        self.dots.is_empty()
+    }
+
+    /// The epoch of the replica which created the delta.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Add the changes in `other` to this delta, so that applying the result
+    /// is the same as applying both. Returns an error without changing this
+    /// delta if the deltas were created by replicas with different configs,
+    /// in different epochs, or after different rebalances of the previous
+    /// epoch.
+    pub fn join(&mut self, other: &Delta<T>) -> Result<(), LseqError> {
+        if other.config != self.config {
+            return Err(LseqError::ConfigMismatch { expected: self.config, found: other.config });
+        }
+        if other.epoch != self.epoch {
+            return Err(LseqError::EpochMismatch { expected: self.epoch, found: other.epoch });
+        }
+        if other.base != self.base {
+            return Err(LseqError::RebalanceConflict { epoch: self.epoch - 1 });
+        }
+
+        for entry in &other.rebalances {
+            if !self.rebalances.iter().any(|(dot, _)| *dot == entry.0) {
+                self.rebalances.push(entry.clone());
+            }
+        }
+        Rebalance::sort(&mut self.rebalances);
+        for (dot, dropped) in &other.dropped {
+            match self.dropped.iter_mut().find(|(seen, _)| seen == dot) {
+                Some((_, contents)) => contents.join(dropped),
+                None => self.dropped.push((*dot, dropped.clone())),
+            }
+        }
+        self.contents.join(&other.contents);
+        self.dots.join(&other.dots);
+        Ok(())
+    }
+}
+
+impl<T: Serialize> Serialize for Delta<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(rename = "Delta")]
+        struct Fields<'a, T> {
+            config: u64,
+            epoch: u64,
+            base: Option<Dot>,
+            rebalances: &'a [(Dot, Rebalance)],
+            dropped: &'a [(Dot, Contents<T>)],
+            dots: &'a Dots,
+            contents: &'a Contents<T>,
+        }
+
+        Fields {
+            config: self.config,
+            epoch: self.epoch,
+            base: self.base,
+            rebalances: &self.rebalances,
+            dropped: &self.dropped,
+            dots: &self.dots,
+            contents: &self.contents,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Ids are only checked against the config when the delta is applied.
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Delta<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Delta<T>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Delta")]
+        struct Fields<T> {
+            config: u64,
+            epoch: u64,
+            base: Option<Dot>,
+            rebalances: Vec<(Dot, Rebalance)>,
+            dropped: Vec<(Dot, ContentsFields<T>)>,
+            dots: Dots,
+            contents: ContentsFields<T>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        if (f.epoch == 0) != f.base.is_none() {
+            return Err(de::Error::custom("delta base doesn't match its epoch"));
+        }
+        let keys: Vec<(u64, Dot)> = f.rebalances.iter().map(|(dot, rebalance)| (rebalance.epoch(), *dot)).collect();
+        if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(de::Error::custom("rebalances out of order"));
+        }
+        let dropped = f
+            .dropped
+            .into_iter()
+            .map(|(dot, contents)| Ok((dot, Contents::from_fields(contents).map_err(de::Error::custom)?)))
+            .collect::<Result<_, D::Error>>()?;
+        Ok(Delta {
+            config: f.config,
+            epoch: f.epoch,
+            base: f.base,
+            rebalances: f.rebalances,
+            dropped,
+            dots: f.dots,
+            contents: Contents::from_fields(f.contents).map_err(de::Error::custom)?,
+        })
+    }
+}
@@ -0,0 +1,496 @@
+//! A compact binary encoding for `Id`s.
+//!
+//! The index at each level is stored in exactly as many bits as are needed for
+//! the width of that level (e.g., 4 bits for the 16 indices of the first level
+//! with the default config). The node and counter for each level are stored as
+//! variable length integers, but only if they differ from the previous level,
+//! which is common since most levels of an id are allocated together.
+
+use crate::{Id, Level, LseqError, NodeConfig, NodeId};
+use std::convert::TryFrom;
+
+impl Id {
+    /// Encode this id using the compact binary encoding. `config` must be the
+    /// config of the `Node`s which allocated the id.
+    ///
+    /// Returns an error if the id fails `Id::validate` for `config` (this
+    /// includes the `Node::begin` and `Node::end` sentinels), since
+    /// `Id::decode` would reject it.
+    pub fn encode(&self, config: &NodeConfig) -> Result<Vec<u8>, LseqError> {
+        self.validate(config)?;
+
+        let mut writer = BitWriter::new();
+        writer.write_varint(self.levels.len() as u128);
+
+        let mut prev: Option<&Level> = None;
+        for (level, l) in self.levels.iter().enumerate() {
+            writer.write_bits(l.index, bits_for(config.width_at(level)));
+
+            match prev {
+                Some(p) if p.node == l.node && p.counter == l.counter => writer.write_bit(true),
+                Some(p) if p.node == l.node => {
+                    writer.write_bit(false);
+                    writer.write_bit(true);
+                    writer.write_varint(u128::from(l.counter));
+                }
+                _ => {
+                    writer.write_bit(false);
+                    writer.write_bit(false);
+                    writer.write_varint(l.node.0);
+                    writer.write_varint(u128::from(l.counter));
+                }
+            }
+            prev = Some(l);
+        }
+
+        Ok(writer.finish())
+    }
+
+    /// Decode an id encoded with `Id::encode`. `bytes` must contain exactly
+    /// one id, encoded exactly as `Id::encode` would, so each id has a single
+    /// encoding. The id must pass `Id::validate`.
+    pub fn decode(bytes: &[u8], config: &NodeConfig) -> Result<Id, LseqError> {
+        let mut reader = BitReader::new(bytes);
+        let depth = reader.read_u64()?;
+        let max_depth = config.max_depth();
+        if depth > max_depth as u64 {
+            return Err(LseqError::DepthLimitExceeded { max_depth });
+        }
+
+        // Each level takes at least one bit, so a corrupt depth can't make
+        // us allocate much more than the input.
+        let mut levels: Vec<Level> = Vec::with_capacity(depth.min(reader.remaining() as u64) as usize);
+        for level in 0..depth as usize {
+            let width = config.width_at(level);
+            let index = reader.read_bits(bits_for(width))?;
+            if index >= width {
+                return Err(LseqError::IndexOutOfWidth { level, index, width });
+            }
+
+            let (node, counter) = if reader.read_bit()? {
+                let prev = levels.last().ok_or(LseqError::InvalidEncoding("no previous level"))?;
+                (prev.node, prev.counter)
+            } else if reader.read_bit()? {
+                let prev = levels.last().ok_or(LseqError::InvalidEncoding("no previous level"))?;
+                let counter = reader.read_u64()?;
+                if counter == prev.counter {
+                    return Err(LseqError::InvalidEncoding("repeated counter"));
+                }
+                (prev.node, counter)
+            } else {
+                let node = NodeId(reader.read_varint()?);
+                if levels.last().is_some_and(|prev| prev.node == node) {
+                    return Err(LseqError::InvalidEncoding("repeated node"));
+                }
+                (node, reader.read_u64()?)
+            };
+            levels.push(Level { index, node, counter });
+        }
+
+        reader.finish()?;
+        let id = Id { levels };
+        id.validate(config)?;
+        Ok(id)
+    }
+}
+
+/// Serialize and deserialize `Id`s using the compact encoding. Like
+/// `Id::encode`, these need the config of the `Node`s which allocated the ids.
+///
+/// To use them with `#[serde(serialize_with = "...", deserialize_with =
+/// "...")]`, wrap them in functions which supply the config:
+///
+/// ```
+/// use lseq::{compact, Id, NodeConfig};
+/// use serde::{Deserializer, Serializer};
+///
+/// fn config() -> NodeConfig {
+///     NodeConfig::builder().initial_width(32).build()
+/// }
+///
+/// fn serialize_id<S: Serializer>(id: &Id, serializer: S) -> Result<S::Ok, S::Error> {
+///     compact::serialize(id, &config(), serializer)
+/// }
+///
+/// fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+///     compact::deserialize(&config(), deserializer)
+/// }
+/// ```
+///
+/// Or use `Encoded` and `Decode` to (de)serialize ids as part of a larger
+/// value.
+pub mod compact {
+    use crate::{Id, NodeConfig};
+    use serde::de::{self, DeserializeSeed, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{self, Serialize, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(id: &Id, config: &NodeConfig, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = id.encode(config).map_err(ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(config: &NodeConfig, deserializer: D) -> Result<Id, D::Error> {
+        let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+        Id::decode(&bytes, config).map_err(de::Error::custom)
+    }
+
+    /// Serializes the id with the compact encoding.
+    pub struct Encoded<'a>(pub &'a Id, pub &'a NodeConfig);
+
+    impl<'a> Serialize for Encoded<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, self.1, serializer)
+        }
+    }
+
+    /// Deserializes an id with the compact encoding, like `ValidateId`.
+    pub struct Decode<'a>(pub &'a NodeConfig);
+
+    impl<'de, 'a> DeserializeSeed<'de> for Decode<'a> {
+        type Value = Id;
+
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Id, D::Error> {
+            deserialize(self.0, deserializer)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an encoded id")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut result = Vec::new();
+            while let Some(b) = seq.next_element()? {
+                result.push(b);
+            }
+            Ok(result)
+        }
+    }
+}
+
+// The number of bits needed to store any index less than `width`.
+fn bits_for(width: u64) -> u32 {
+    64 - (width - 1).leading_zeros()
+}
+
+// Writes bits most significant first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Number of bits used in the last byte, 0 means a new byte is needed.
+    used: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), used: 0 }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    // Groups of 7 bits, least significant first, each preceded by a bit which
+    // is set if there are more groups to follow.
+    fn write_varint(&mut self, mut value: u128) {
+        loop {
+            let group = (value & 0x7f) as u64;
+            value >>= 7;
+            self.write_bit(value != 0);
+            self.write_bits(group, 7);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // Position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, LseqError> {
+        let byte = self.bytes.get(self.pos / 8).ok_or(LseqError::InvalidEncoding("unexpected end of input"))?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, bits: u32) -> Result<u64, LseqError> {
+        let mut result = 0;
+        for _ in 0..bits {
+            result = (result << 1) | self.read_bit()? as u64;
+        }
+        Ok(result)
+    }
+
+    fn read_varint(&mut self) -> Result<u128, LseqError> {
+        let mut result: u128 = 0;
+        let mut shift = 0;
+        loop {
+            let more = self.read_bit()?;
+            let group = u128::from(self.read_bits(7)?);
+            if shift >= 128 || (shift > 0 && group >> (128 - shift) != 0) {
+                return Err(LseqError::InvalidEncoding("varint overflow"));
+            }
+            if !more && shift > 0 && group == 0 {
+                return Err(LseqError::InvalidEncoding("varint with a trailing zero group"));
+            }
+            result |= group << shift;
+            shift += 7;
+            if !more {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn read_u64(&mut self) -> Result<u64, LseqError> {
+        u64::try_from(self.read_varint()?).map_err(|_| LseqError::InvalidEncoding("number out of range"))
+    }
+
+    // The number of bits not yet read.
+    fn remaining(&self) -> usize {
+        self.bytes.len() * 8 - self.pos
+    }
+
+    // Check that only padding remains, and that it's zero.
+    fn finish(&self) -> Result<(), LseqError> {
+        if self.pos.div_ceil(8) != self.bytes.len() {
+            return Err(LseqError::InvalidEncoding("trailing bytes"));
+        }
+        if !self.pos.is_multiple_of(8) && self.bytes[self.pos / 8] & (0xff >> (self.pos % 8)) != 0 {
+            return Err(LseqError::InvalidEncoding("non-zero padding"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{allocate_ids, id};
+    use crate::{Node, WidthGrowth};
+    use bincode::{deserialize, serialize};
+    use serde_derive::{Deserialize, Serialize};
+
+    fn random_ids(config: &NodeConfig, b: NodeId) -> Vec<Id> {
+        let mut a = Node::with_config(NodeId::new(1), config.clone());
+        let mut b = Node::with_config(b, config.clone());
+        allocate_ids(&mut a, &mut b, 500)
+    }
+
+    #[test]
+    fn test_bits_for() {
+        assert_eq!(bits_for(2), 1);
+        assert_eq!(bits_for(16), 4);
+        assert_eq!(bits_for(17), 5);
+        assert_eq!(bits_for(32), 5);
+    }
+
+    #[test]
+    fn test_varint() {
+        let values = [0, 1, 127, 128, 300, u128::from(u32::MAX), u128::from(u64::MAX), u128::MAX];
+        let mut writer = BitWriter::new();
+        writer.write_bit(true);
+        for v in &values {
+            writer.write_varint(*v);
+        }
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert!(reader.read_bit().unwrap());
+        for v in &values {
+            assert_eq!(reader.read_varint().unwrap(), *v);
+        }
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let configs = [
+            NodeConfig::new(),
+            NodeConfig::builder().initial_width(5).growth(WidthGrowth::Linear(3)).build(),
+            NodeConfig::builder().initial_width(8).growth(WidthGrowth::Constant).max_depth(64).build(),
+        ];
+        for config in &configs {
+            for id in random_ids(config, NodeId::random()) {
+                let bytes = id.encode(config).unwrap();
+                assert_eq!(Id::decode(&bytes, config).unwrap(), id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_size() {
+        let config = NodeConfig::new();
+        let ids = random_ids(&config, NodeId::new(70000));
+        let compact: usize = ids.iter().map(|id| id.encode(&config).unwrap().len()).sum();
+        let bincode: usize = ids.iter().map(|id| serialize(id).unwrap().len()).sum();
+        assert!(compact * 5 < bincode, "{} vs {}", compact, bincode);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let config = NodeConfig::new();
+        let node = Node::new(NodeId::new(0));
+        assert_eq!(
+            node.end().encode(&config),
+            Err(LseqError::IndexOutOfWidth { level: 0, index: 16, width: 16 })
+        );
+        assert_eq!(node.begin().encode(&config), Err(LseqError::ZeroLastIndex));
+
+        let last = random_ids(&config, NodeId::random()).pop().unwrap();
+        let bytes = last.encode(&config).unwrap();
+        assert!(Id::decode(&bytes[..bytes.len() - 1], &config).is_err());
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(Id::decode(&long, &config), Err(LseqError::InvalidEncoding("trailing bytes")));
+
+        // Decoding with a narrower config.
+        let narrow = NodeConfig::builder().initial_width(9).build();
+        let wide = id(&[(15, 0, 1)]);
+        let bytes = wide.encode(&config).unwrap();
+        assert_eq!(
+            Id::decode(&bytes, &narrow),
+            Err(LseqError::IndexOutOfWidth { level: 0, index: 15, width: 9 })
+        );
+
+        let shallow = NodeConfig::builder().max_depth(1).build();
+        let deep = id(&[(1, 0, 1), (1, 0, 1)]);
+        let bytes = deep.encode(&config).unwrap();
+        assert_eq!(Id::decode(&bytes, &shallow), Err(LseqError::DepthLimitExceeded { max_depth: 1 }));
+        assert_eq!(deep.encode(&shallow), Err(LseqError::DepthLimitExceeded { max_depth: 1 }));
+
+        // A huge depth with no levels after it.
+        let mut writer = BitWriter::new();
+        writer.write_varint(u128::from(u64::MAX));
+        let unlimited = NodeConfig::builder().max_depth(usize::MAX).build();
+        assert!(Id::decode(&writer.finish(), &unlimited).is_err());
+    }
+
+    #[test]
+    fn test_decode_non_canonical() {
+        let config = NodeConfig::new();
+        // Writes the levels of an id after its depth, with a new node and
+        // counter for each.
+        let write_levels = |writer: &mut BitWriter, levels: &[(u64, u32)]| {
+            for (level, &(index, counter)) in levels.iter().enumerate() {
+                writer.write_bits(index, bits_for(config.width_at(level)));
+                writer.write_bit(false);
+                writer.write_bit(level > 0);
+                if level == 0 {
+                    writer.write_varint(0);
+                }
+                writer.write_varint(u128::from(counter));
+            }
+        };
+
+        // 30 bits, so the last byte has 2 bits of padding.
+        let bytes = id(&[(1, 0, 1)]).encode(&config).unwrap();
+        let mut padded = bytes.clone();
+        *padded.last_mut().unwrap() |= 1;
+        assert_eq!(Id::decode(&padded, &config), Err(LseqError::InvalidEncoding("non-zero padding")));
+
+        // A depth of 1 written as two groups.
+        let mut writer = BitWriter::new();
+        writer.write_bit(true);
+        writer.write_bits(1, 7);
+        writer.write_bit(false);
+        writer.write_bits(0, 7);
+        write_levels(&mut writer, &[(1, 1)]);
+        assert_eq!(
+            Id::decode(&writer.finish(), &config),
+            Err(LseqError::InvalidEncoding("varint with a trailing zero group"))
+        );
+
+        // A new counter which is the same as the previous level's.
+        let mut writer = BitWriter::new();
+        writer.write_varint(2);
+        write_levels(&mut writer, &[(1, 1), (1, 1)]);
+        assert_eq!(Id::decode(&writer.finish(), &config), Err(LseqError::InvalidEncoding("repeated counter")));
+
+        // Ids which `Id::validate` rejects.
+        let mut writer = BitWriter::new();
+        writer.write_varint(0);
+        assert_eq!(Id::decode(&writer.finish(), &config), Err(LseqError::EmptyId));
+        let mut writer = BitWriter::new();
+        writer.write_varint(1);
+        write_levels(&mut writer, &[(0, 1)]);
+        assert_eq!(Id::decode(&writer.finish(), &config), Err(LseqError::ZeroLastIndex));
+
+        // The same ids, encoded canonically, decode.
+        let mut writer = BitWriter::new();
+        writer.write_varint(2);
+        write_levels(&mut writer, &[(1, 1), (1, 2)]);
+        assert_eq!(Id::decode(&writer.finish(), &config), Ok(id(&[(1, 0, 1), (1, 0, 2)])));
+        assert_eq!(Id::decode(&bytes, &config), Ok(id(&[(1, 0, 1)])));
+    }
+
+    #[test]
+    fn test_serde_adapter() {
+        use bincode::Options;
+        use serde::de::DeserializeSeed;
+
+        fn config() -> NodeConfig {
+            NodeConfig::builder().initial_width(5).growth(WidthGrowth::Linear(3)).build()
+        }
+        fn serialize_id<S: serde::Serializer>(id: &Id, serializer: S) -> Result<S::Ok, S::Error> {
+            crate::compact::serialize(id, &config(), serializer)
+        }
+        fn deserialize_id<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+            crate::compact::deserialize(&config(), deserializer)
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Entry {
+            #[serde(serialize_with = "serialize_id", deserialize_with = "deserialize_id")]
+            id: Id,
+            value: char,
+        }
+
+        for id in random_ids(&config(), NodeId::random()) {
+            let bytes = bincode::options().serialize(&crate::compact::Encoded(&id, &config())).unwrap();
+            let mut de = bincode::Deserializer::from_slice(&bytes, bincode::options());
+            assert_eq!(crate::compact::Decode(&config()).deserialize(&mut de).unwrap(), id);
+
+            let entry = Entry { id, value: 'a' };
+            let bytes = serialize(&entry).unwrap();
+            assert_eq!(deserialize::<Entry>(&bytes).unwrap(), entry);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors from allocating ids and applying ops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LseqError {
+    /// An id with no indices was used as a bound.
+    EmptyId,
+    /// The lower bound was greater than the upper bound.
+    BoundsReversed,
+    /// An id was, or would have to be, deeper than `NodeConfig::max_depth`.
+    DepthLimitExceeded { max_depth: usize },
+    /// An id had an index which is not valid at its level.
+    IndexOutOfWidth { level: usize, index: u64, width: u64 },
+    /// The last index of an id was 0, no `Node` allocates such ids.
+    ZeroLastIndex,
+    /// An `IdBlock` was empty or its ids were not all valid, or an op did not
+    /// have one value for each id in its block.
+    InvalidBlock(&'static str),
+    /// An op was created by a replica with a different `NodeConfig`. The
+    /// values are `NodeConfig::fingerprint`s.
+    ConfigMismatch { expected: u64, found: u64 },
+    /// Bytes passed to `Id::decode` or `Id::from_sort_key`, or a string passed
+    /// to `Id::from_sort_string` or `FromStr`, were not a valid id.
+    InvalidEncoding(&'static str),
+    /// A deserialized `LSeq` or `Delta`, or an op, was not consistent, e.g.,
+    /// its elements were not in order.
+    InvalidState(&'static str),
+    /// Ops, deltas or states from different epochs (see `LSeq::rebalance`)
+    /// were combined, and the rebalances needed to translate between them
+    /// were not available.
+    EpochMismatch { expected: u64, found: u64 },
+    /// Deltas created after different rebalances of the same epoch were
+    /// joined, see `Delta::join`. Apply them to an `LSeq` instead, which
+    /// resolves concurrent rebalances (see `LSeq::rebalance`).
+    RebalanceConflict { epoch: u64 },
+    /// An op based on a rebalance which hasn't arrived yet was rejected since
+    /// too many such ops are waiting, see `LSeq::apply_remote`.
+    PendingLimit { limit: usize },
+    /// An internal invariant did not hold; this is a bug in lseq or in an
+    /// `AllocationStrategy`.
+    InvariantBroken(&'static str),
+}
+
+impl fmt::Display for LseqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LseqError::EmptyId => write!(f, "empty id"),
+            LseqError::BoundsReversed => write!(f, "lower bound is greater than upper bound"),
+            LseqError::DepthLimitExceeded { max_depth } => {
+                write!(f, "id exceeds the maximum depth ({})", max_depth)
+            }
+            LseqError::IndexOutOfWidth { level, index, width } => {
+                write!(f, "index {} at level {} is out of width ({})", index, level, width)
+            }
+            LseqError::ZeroLastIndex => write!(f, "last index of id is 0"),
+            LseqError::InvalidBlock(msg) => write!(f, "invalid block: {}", msg),
+            LseqError::ConfigMismatch { expected, found } => {
+                write!(f, "config fingerprint mismatch: expected {:x}, found {:x}", expected, found)
+            }
+            LseqError::InvalidEncoding(msg) => write!(f, "invalid encoded id: {}", msg),
+            LseqError::InvalidState(msg) => write!(f, "invalid state: {}", msg),
+            LseqError::EpochMismatch { expected, found } => {
+                write!(f, "epoch mismatch: expected {}, found {}", expected, found)
+            }
+            LseqError::RebalanceConflict { epoch } => write!(f, "conflicting rebalances of epoch {}", epoch),
+            LseqError::PendingLimit { limit } => write!(f, "more than {} ops waiting for a rebalance", limit),
+            LseqError::InvariantBroken(msg) => write!(f, "internal invariant broken: {}", msg),
+        }
+    }
+}
+
+impl Error for LseqError {}
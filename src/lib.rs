@@ -1,226 +1,625 @@
-#![feature(const_vec_new)]
-
-use bit_vec::BitVec;
-use rand::{thread_rng, Rng};
+use rand::prng::XorShiftRng;
+use rand::{thread_rng, Rng, RngCore, SeedableRng};
+use serde::de::{self, DeserializeSeed, Deserializer};
 use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+use std::str::FromStr;
+
+pub use crate::block::IdBlock;
+pub use crate::config::{NodeConfig, NodeConfigBuilder, WidthGrowth};
+pub use crate::delta::Delta;
+pub use crate::encoding::compact;
+pub use crate::error::LseqError;
+pub use crate::rebalance::Rebalance;
+pub use crate::seq::{LSeq, Op, OpKind};
+pub use crate::strategy::{AllocationStrategy, BoundaryMinus, BoundaryPlus, HashMix, RandomMix};
+pub use crate::version::VersionVector;
 
-const INITIAL_WIDTH: u64 = 16;
-// FIXME currently cannot be customised.
-const DEFAULT_BOUNDARY: u64 = 10;
+mod block;
+mod config;
+mod contents;
+mod delta;
+mod encoding;
+mod error;
+mod rebalance;
+mod seq;
+mod sort_key;
+mod strategy;
+#[cfg(test)]
+mod test_util;
+mod tree;
+mod version;
 
 /// Generates unique ids. There should be one `Node` per replicated instance.
-pub struct Node {
+///
+/// A `Node` can be serialized and later restored to resume allocating ids
+/// where it left off. The source of randomness is not serialized, a restored
+/// node uses `thread_rng` until `set_rng` or `set_seed` is called.
+#[derive(Serialize, Deserialize)]
+pub struct Node<S = RandomMix> {
     pub id: NodeId,
-    // True = upper, false = lower
-    directions: BitVec,
-    initial_width: u64,
+    // Incremented for every allocation.
+    counter: u64,
+    config: NodeConfig,
+    strategy: S,
+    // None means use `thread_rng`.
+    #[serde(skip)]
+    rng: Option<Box<dyn RngCore + Send>>,
 }
 
 impl Node {
     pub fn new(id: NodeId) -> Node {
-        let mut result = Node {
+        Node::with_config(id, NodeConfig::new())
+    }
+
+    pub fn with_config(id: NodeId, config: NodeConfig) -> Node {
+        Node::with_strategy(id, config, RandomMix::new())
+    }
+}
+
+impl<S: AllocationStrategy> Node<S> {
+    pub fn with_strategy(id: NodeId, config: NodeConfig, strategy: S) -> Node<S> {
+        Node {
             id,
-            directions: BitVec::new(),
-            initial_width: INITIAL_WIDTH,
-        };
-        result.level_direction(0);
-        result
+            counter: 0,
+            config,
+            strategy,
+            rng: None,
+        }
+    }
+
+    /// Use `rng` as the source of randomness for allocating ids, rather than
+    /// `thread_rng`.
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) {
+        self.rng = Some(Box::new(rng));
+    }
+
+    /// Make id allocation deterministic. Nodes with the same id, config,
+    /// strategy and seed, which are given the same sequence of requests, will
+    /// produce the same ids.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.set_rng(seeded_rng(seed));
     }
 
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
+
+    /// A sentinel id which is less than any id allocated by any `Node`.
     pub fn begin(&self) -> Id {
         Id {
-            indices: vec![0],
-            node: self.id,
+            levels: vec![Level::sentinel(0)],
         }
     }
 
-    pub fn new_id_with_bounds(&mut self, lower_bound: &Id, upper_bound: &Id) -> Id {
-        assert!(lower_bound.depth() > 0);
-        assert!(upper_bound.depth() > 0);
-        assert!(lower_bound <= upper_bound, "{:?} > {:?}", lower_bound, upper_bound);
+    /// A sentinel id which is greater than any id allocated by a `Node` with
+    /// the same config. It can be used as an upper bound, but not a lower
+    /// bound.
+    pub fn end(&self) -> Id {
+        Id {
+            levels: vec![Level::sentinel(self.width_at(0))],
+        }
+    }
 
-        // In the rest of the functions, we assume that the bounds can't be equal.
-        if lower_bound == upper_bound {
-            let last_level = lower_bound.depth() - 1;
-            let last_level_index = lower_bound.indices[last_level];
-            let width = self.width_at(last_level);
-            if last_level_index + 1 < self.width_at(last_level) {
-                let new_index =
-                    self.pick_index(last_level, last_level_index, width);
-                return self.truncate_and_replace_index(lower_bound, last_level, new_index);
-            } else {
-                let new_index = self.pick_index(last_level + 1, 0, self.width_at(last_level + 1));
-                return self.append_index(&lower_bound, new_index);
-            }
+    /// Allocate a new id greater than `id`, with no upper bound.
+    pub fn new_id_after(&mut self, id: &Id) -> Id {
+        self.new_id_between(Some(id), None)
+    }
+
+    /// Allocate a new id less than `id`, with no lower bound.
+    pub fn new_id_before(&mut self, id: &Id) -> Id {
+        self.new_id_between(None, Some(id))
+    }
+
+    /// Allocate a new id strictly between `lower_bound` and `upper_bound`.
+    /// `None` means the bound is open, i.e., `begin` or `end`.
+    ///
+    /// Panics if the bounds are invalid, see `try_new_id_between`.
+    pub fn new_id_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>) -> Id {
+        match self.try_new_id_between(lower_bound, upper_bound) {
+            Ok(id) => id,
+            Err(e) => panic!("{} (bounds: {:?}, {:?})", e, lower_bound, upper_bound),
         }
+    }
 
-        // This loop walks up the bounds in tandem until one runs out of levels, or
-        // the two are diverging.
-        let mut level = 0;
-        loop {
-            assert!(lower_bound.depth() > level);
-            assert!(upper_bound.depth() > level);
+    /// Like `new_id_between`, but returns an error rather than panicking.
+    /// Unlike `try_new_id_with_bounds`, it is an error for the bounds to be
+    /// equal.
+    pub fn try_new_id_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>) -> Result<Id, LseqError> {
+        let (lower_bound, upper_bound) = self.open_bounds(lower_bound, upper_bound);
+        self.check_bounds(&lower_bound, &upper_bound)?;
 
-            if level == lower_bound.depth() - 1 || level == upper_bound.depth() - 1 
-                || lower_bound.indices[level] < upper_bound.indices[level]
-            {
-                return self.new_id_at_level_bounded(level, lower_bound, upper_bound);
-            }
+        let counter = self.counter + 1;
+        let (mut levels, low, high) = self.find_room(&lower_bound, &upper_bound, 1, counter)?;
+        let index = self.pick_index(levels.len(), low, high)?;
+        levels.push(Level { index, node: self.id, counter });
+
+        self.counter = counter;
+        Ok(Id { levels })
+    }
 
-            assert!(lower_bound.indices[level] == upper_bound.indices[level]);
-            level += 1;
+    /// Allocate `n` strictly increasing ids between `lower_bound` and
+    /// `upper_bound` (see `new_id_between`).
+    ///
+    /// The ids are evenly spread over the shallowest level of the tree which
+    /// has room for all of them, which gives much shorter ids than allocating
+    /// each id after the previous one.
+    ///
+    /// Panics if the bounds are invalid, see `try_new_ids_between`.
+    pub fn new_ids_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>, n: usize) -> Vec<Id> {
+        match self.try_new_ids_between(lower_bound, upper_bound, n) {
+            Ok(ids) => ids,
+            Err(e) => panic!("{} (bounds: {:?}, {:?})", e, lower_bound, upper_bound),
         }
     }
 
-    fn new_id_at_level_bounded(&mut self, level: usize, lower_bound: &Id, upper_bound: &Id) -> Id {
-        assert!(lower_bound < upper_bound);
-        assert!(lower_bound.depth() > level && upper_bound.depth() > level);
-        let level_lower_bound = lower_bound.indices[level];
-        let level_upper_bound = upper_bound.indices[level];
+    /// Like `new_ids_between`, but returns an error rather than panicking.
+    pub fn try_new_ids_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>, n: usize) -> Result<Vec<Id>, LseqError> {
+        if n == 0 {
+            let (lower_bound, upper_bound) = self.open_bounds(lower_bound, upper_bound);
+            self.check_bounds(&lower_bound, &upper_bound)?;
+            return Ok(Vec::new());
+        }
+        Ok(self.try_new_block_between(lower_bound, upper_bound, n)?.iter().collect())
+    }
 
-        if level_lower_bound + 1 < level_upper_bound {
-            // there is room to add an id between lower_bound and upper_bound
-            let new_index = self.pick_index(level, level_lower_bound, level_upper_bound);
-            return self.truncate_and_replace_index(lower_bound, level, new_index);
+    /// Like `new_ids_between`, but returns the ids as an `IdBlock`, which is
+    /// much smaller than a `Vec` of ids.
+    ///
+    /// Panics if `n` is 0 or the bounds are invalid, see
+    /// `try_new_block_between`.
+    pub fn new_block_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>, n: usize) -> IdBlock {
+        match self.try_new_block_between(lower_bound, upper_bound, n) {
+            Ok(block) => block,
+            Err(e) => panic!("{} (bounds: {:?}, {:?})", e, lower_bound, upper_bound),
         }
+    }
 
-        if level_lower_bound <= level_upper_bound {
-            assert!(level_lower_bound + 1 >= level_upper_bound);
-            if lower_bound.depth() > level + 1 || upper_bound.depth() == level + 1 {
-                return self.new_id_at_level_bounded_below(level + 1, lower_bound);
-            }
+    /// Like `new_block_between`, but returns an error rather than panicking.
+    pub fn try_new_block_between(&mut self, lower_bound: Option<&Id>, upper_bound: Option<&Id>, n: usize) -> Result<IdBlock, LseqError> {
+        let (lower_bound, upper_bound) = self.open_bounds(lower_bound, upper_bound);
+        self.check_bounds(&lower_bound, &upper_bound)?;
+        if n == 0 {
+            return Err(LseqError::InvalidBlock("empty block"));
         }
 
-        assert!((lower_bound.depth() == level + 1 || level_lower_bound < level_upper_bound) && upper_bound.depth() > level + 1);
-        let lhs = self.append_index(lower_bound, 0);
-        self.new_id_at_level_bounded(level + 1, &lhs, upper_bound)
+        // All the ids share a counter, they are distinguished by their index.
+        let counter = self.counter + 1;
+        let (mut levels, low, high) = self.find_room(&lower_bound, &upper_bound, n as u64, counter)?;
+        let step = (high - low) / (n as u64 + 1);
+        levels.push(Level { index: low + step, node: self.id, counter });
+
+        self.counter = counter;
+        Ok(IdBlock::new(Id { levels }, step, n))
     }
 
-    // The implicit upper bound here is the next index on level with no further indices
-    fn new_id_at_level_bounded_below(&mut self, level: usize, lower_bound: &Id) -> Id {
-        assert!(lower_bound.depth() >= level);
-        let width = self.width_at(level);
-        if lower_bound.depth() > level && lower_bound.indices[level] < width - 1 {
-            let rhs = self.truncate_and_replace_index(lower_bound, level, width - 1);
-            self.new_id_at_level_bounded(level, lower_bound, &rhs)
-        } else {
-            let new_index = self.pick_index(level, 0, width);
-            self.append_index(&lower_bound, new_index)
+    /// Allocate a new id strictly between `lower_bound` and `upper_bound`. If
+    /// the bounds are equal, the new id is greater than them (with no upper
+    /// bound).
+    ///
+    /// Panics if the bounds are invalid, see `try_new_id_with_bounds`.
+    pub fn new_id_with_bounds(&mut self, lower_bound: &Id, upper_bound: &Id) -> Id {
+        match self.try_new_id_with_bounds(lower_bound, upper_bound) {
+            Ok(id) => id,
+            Err(e) => panic!("{} (bounds: {:?}, {:?})", e, lower_bound, upper_bound),
         }
     }
 
-    fn level_direction(&mut self, level: usize) -> bool {
-        while level >= self.directions.len() {
-            let result = random_bool();
-            self.directions.push(result);
+    /// Like `new_id_with_bounds`, but returns an error rather than panicking
+    /// if the bounds are invalid (for example, if they come from another
+    /// replica with a different config) or if there is no room for a new id.
+    pub fn try_new_id_with_bounds(&mut self, lower_bound: &Id, upper_bound: &Id) -> Result<Id, LseqError> {
+        if lower_bound == upper_bound {
+            self.try_new_id_between(Some(lower_bound), None)
+        } else {
+            self.try_new_id_between(Some(lower_bound), Some(upper_bound))
         }
+    }
 
-        self.directions[level]
+    // Replace open bounds with `begin` or `end`.
+    fn open_bounds(&self, lower_bound: Option<&Id>, upper_bound: Option<&Id>) -> (Id, Id) {
+        (
+            lower_bound.cloned().unwrap_or_else(|| self.begin()),
+            upper_bound.cloned().unwrap_or_else(|| self.end()),
+        )
     }
 
-    fn pick_index(&mut self, level: usize, lower_bound: u64, upper_bound: u64) -> u64 {
-        assert!(lower_bound + 1 < upper_bound, "{} < {}", lower_bound + 1, upper_bound);
-        if self.level_direction(level) {
-            let mut boundary = upper_bound.saturating_sub(DEFAULT_BOUNDARY + 1);
-            if boundary < lower_bound {
-                boundary = lower_bound;
+    // Walks down the tree from the root looking for the shallowest level with
+    // room for `n` ids between the bounds. Returns the levels of the prefix of
+    // the new ids, and the range (exclusive) of indices available at the
+    // next level.
+    fn find_room(&self, lower_bound: &Id, upper_bound: &Id, n: u64, counter: u64) -> Result<(Vec<Level>, u64, u64), LseqError> {
+        let mut prefix = Vec::new();
+        // Whether `prefix` is equal to the prefix of the bound with the same
+        // depth. If not, the prefix is strictly between the bounds, and the
+        // bound can be ignored.
+        let mut lower_tight = true;
+        let mut upper_tight = true;
+
+        loop {
+            let level = prefix.len();
+            let max_depth = self.config.max_depth();
+            if level >= max_depth {
+                return Err(LseqError::DepthLimitExceeded { max_depth });
             }
-            random_range(boundary, upper_bound)
-        } else {
-            let mut boundary = lower_bound + DEFAULT_BOUNDARY;
-            if boundary > upper_bound {
-                boundary = upper_bound;
+
+            let lower = if lower_tight { lower_bound.levels.get(level) } else { None };
+            let upper = if upper_tight {
+                // If the prefix is equal to the whole of the upper bound, it
+                // could not be less than it.
+                let upper = upper_bound.levels.get(level);
+                ensure(upper.is_some(), "prefix is not less than upper bound")?;
+                upper
+            } else {
+                None
+            };
+
+            let low = lower.map(|l| l.index).unwrap_or(0);
+            let high = upper.map(|l| l.index).unwrap_or_else(|| self.width_at(level));
+            if high > low && high - low > n {
+                return Ok((prefix, low, high));
             }
-            random_range(lower_bound, boundary)
+
+            // Not enough room at this level, extend the prefix and try the
+            // next level.
+            let next = if high > low + 1 {
+                // Some room, use it to escape both bounds.
+                Level { index: low + 1, node: self.id, counter }
+            } else if let Some(lower) = lower {
+                lower.clone()
+            } else if high == 1 {
+                Level { index: 0, node: self.id, counter }
+            } else {
+                // Only reachable if the upper bound has index 0 at this level,
+                // in which case it must have more levels (the last level of
+                // an allocated id never has index 0).
+                match upper {
+                    Some(upper) => upper.clone(),
+                    None => return Err(LseqError::InvariantBroken("upper bound has no room")),
+                }
+            };
+            lower_tight = lower_tight && lower_bound.levels.get(level) == Some(&next);
+            upper_tight = upper_tight && upper_bound.levels.get(level) == Some(&next);
+            prefix.push(next);
         }
     }
 
-    fn width_at(&self, level: usize) -> u64 {
-        self.initial_width * 2_u64.pow(level as u32)
+    // Checks that the bounds can be used for allocating ids.
+    fn check_bounds(&self, lower_bound: &Id, upper_bound: &Id) -> Result<(), LseqError> {
+        self.check_bound(lower_bound)?;
+        self.check_bound(upper_bound)?;
+        if lower_bound >= upper_bound || self.is_end(lower_bound) {
+            return Err(LseqError::BoundsReversed);
+        }
+        Ok(())
     }
 
-    fn append_index(&self, id: &Id, new_index: u64) -> Id {
-        // FIXME could be more efficient than clone here by making the new indices
-        // have the capacity of id.indices.len() + 1.
-        let mut new_id = id.clone();
-        new_id.node = self.id;
-        new_id.indices.push(new_index);
-        new_id
+    // Like `Id::validate`, but allows `begin` and `end`.
+    fn check_bound(&self, id: &Id) -> Result<(), LseqError> {
+        if self.is_end(id) || *id == self.begin() {
+            return Ok(());
+        }
+        id.validate(&self.config)
     }
 
-    fn truncate_and_replace_index(&self, id: &Id, level: usize, new_index: u64) -> Id {
-        assert!(level < id.indices.len());
-        let mut new_id = id.clone();
-        new_id.node = self.id;
-        new_id.indices[level] = new_index;
-        new_id.indices.truncate(level + 1);
-        new_id
+    fn is_end(&self, id: &Id) -> bool {
+        id.levels.len() == 1 && id.levels[0].index == self.width_at(0)
+    }
+
+    fn pick_index(&mut self, level: usize, lower_bound: u64, upper_bound: u64) -> Result<u64, LseqError> {
+        ensure(lower_bound + 1 < upper_bound, "no room to pick an index")?;
+        let index = match self.rng {
+            Some(ref mut rng) => self.strategy.pick_index(&self.config, rng.as_mut(), level, lower_bound, upper_bound),
+            None => self.strategy.pick_index(&self.config, &mut thread_rng(), level, lower_bound, upper_bound),
+        };
+        ensure(index > lower_bound && index < upper_bound, "strategy picked an index outside the bounds")?;
+        Ok(index)
+    }
+
+    fn width_at(&self, level: usize) -> u64 {
+        self.config.width_at(level)
     }
 }
 
-/// Identifies a `Node`. Supplied by the client and must be globally unique.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
-pub struct NodeId(u32);
+/// Identifies a `Node`, must be unique among the replicas of a sequence.
+///
+/// Node ids are 128 bits, so they can either be assigned by the application
+/// (e.g., from a 32 or 64 bit counter) or generated with `NodeId::random`,
+/// which lets replicas join without coordination. Ids compare by numeric
+/// value, whatever width they were created from.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct NodeId(u128);
 
 impl NodeId {
-    pub fn new(i: u32) -> NodeId {
+    pub fn new(i: u128) -> NodeId {
+        NodeId(i)
+    }
+
+    /// A random node id, like a version 4 UUID the chance of two random ids
+    /// colliding is negligible.
+    pub fn random() -> NodeId {
+        let mut rng = thread_rng();
+        NodeId((u128::from(rng.next_u64()) << 64) | u128::from(rng.next_u64()))
+    }
+
+    pub fn value(self) -> u128 {
+        self.0
+    }
+}
+
+impl From<u32> for NodeId {
+    fn from(i: u32) -> NodeId {
+        NodeId(u128::from(i))
+    }
+}
+
+impl From<u64> for NodeId {
+    fn from(i: u64) -> NodeId {
+        NodeId(u128::from(i))
+    }
+}
+
+impl From<u128> for NodeId {
+    fn from(i: u128) -> NodeId {
         NodeId(i)
     }
 }
 
 /// An LSeq Id, created by a `Node`.
-// FIXME could optimise Eq/ParialEq by comparing pointer value of indices
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+///
+/// Each level of an id records the `Node` which allocated it and a counter,
+/// so ids allocated by different nodes (or by the same node at different
+/// times) are always distinct, even if their indices are the same. There is
+/// always room for a new id between two different ids.
+// FIXME could optimise Eq/ParialEq by comparing pointer value of levels
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
 pub struct Id {
-    // Ordering of fields is for derive(Ord).
+    // The path through the tree of identifiers, the nth entry in the `Vec`
+    // specifies a node in the nth level of the tree. derive(Ord) compares
+    // level by level, an id is greater than any of its prefixes.
+    levels: Vec<Level>,
+}
+
+// Ordering of fields is for derive(Ord).
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
+struct Level {
+    index: u64,
+    // The `Node` which allocated this level, and the value of that node's
+    // counter when it did so.
+    node: NodeId,
+    counter: u64,
+}
 
-    // Indices into the tree of identifiers. The nth entry in the `Vec` specifies
-    // a node in the nth level of the tree.
-    indices: Vec<u64>,
-    // The `Node` which created this id.
-    pub node: NodeId,
+impl Level {
+    fn sentinel(index: u64) -> Level {
+        Level { index, node: NodeId(0), counter: 0 }
+    }
 }
 
 impl Id {
-    fn depth(&self) -> usize {
-        self.indices.len()
+    /// The `Node` which created this id, `None` for the empty id.
+    pub fn node(&self) -> Option<NodeId> {
+        self.levels.last().map(|l| l.node)
+    }
+
+    /// The number of levels in this id.
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Check that this id could have been allocated by a `Node` with `config`.
+    /// Ids received from other replicas should be validated before they are
+    /// used, see also `ValidateId`.
+    ///
+    /// The id must not be empty or deeper than `NodeConfig::max_depth`, each
+    /// index must be less than the width of its level, and the last index
+    /// must not be 0. `Node::begin` and `Node::end` are not valid.
+    pub fn validate(&self, config: &NodeConfig) -> Result<(), LseqError> {
+        self.check_levels(config)?;
+        if self.levels.last().map(|l| l.index) == Some(0) {
+            return Err(LseqError::ZeroLastIndex);
+        }
+        Ok(())
+    }
+
+    fn check_levels(&self, config: &NodeConfig) -> Result<(), LseqError> {
+        if self.depth() == 0 {
+            return Err(LseqError::EmptyId);
+        }
+        let max_depth = config.max_depth();
+        if self.depth() > max_depth {
+            return Err(LseqError::DepthLimitExceeded { max_depth });
+        }
+        for (level, l) in self.levels.iter().enumerate() {
+            let width = config.width_at(level);
+            if l.index >= width {
+                return Err(LseqError::IndexOutOfWidth { level, index: l.index, width });
+            }
+        }
+        Ok(())
+    }
+
+    /// The index at each level of this id.
+    pub fn indices(&self) -> impl Iterator<Item = u64> + '_ {
+        self.levels.iter().map(|l| l.index)
+    }
+
+    /// The first `level` levels of this id, or the whole id if it has fewer
+    /// levels.
+    pub fn prefix(&self, level: usize) -> Id {
+        Id { levels: self.levels.iter().take(level).cloned().collect() }
+    }
+
+    /// The number of leading levels (index, node and counter) which this id
+    /// has in common with `other`.
+    pub fn common_prefix_len(&self, other: &Id) -> usize {
+        self.levels.iter().zip(&other.levels).take_while(|(a, b)| a == b).count()
+    }
+
+    /// True if `other` is a strict extension of this id, i.e., this id is a
+    /// proper prefix of `other`.
+    pub fn is_ancestor_of(&self, other: &Id) -> bool {
+        self.depth() < other.depth() && self.common_prefix_len(other) == self.depth()
+    }
+
+    /// True if this id is strictly greater than `lower` and strictly less than
+    /// `upper`.
+    pub fn is_between(&self, lower: &Id, upper: &Id) -> bool {
+        lower < self && self < upper
     }
 }
 
-fn random_bool() -> bool {
-    let mut rng = thread_rng();
+/// Formats as the levels of the id separated by `.`, each level is written as
+/// `index@node:counter`, e.g., `5@2:1.32@3:7`. If a level has the same node
+/// and counter as the previous level, they are omitted, e.g., `5@2:1.32.100`.
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut prev: Option<&Level> = None;
+        for l in &self.levels {
+            if prev.is_some() {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", l.index)?;
+            match prev {
+                Some(p) if p.node == l.node && p.counter == l.counter => {}
+                _ => write!(f, "@{}:{}", l.node, l.counter)?,
+            }
+            prev = Some(l);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the format written by `Display`.
+impl FromStr for Id {
+    type Err = LseqError;
+
+    fn from_str(s: &str) -> Result<Id, LseqError> {
+        let mut levels: Vec<Level> = Vec::new();
+        if s.is_empty() {
+            return Ok(Id { levels });
+        }
+        for level in s.split('.') {
+            let (index, node, counter) = match level.find('@') {
+                Some(at) => {
+                    let (node, counter) = level[at + 1..]
+                        .split_once(':')
+                        .ok_or(LseqError::InvalidEncoding("expected `:` after node"))?;
+                    (&level[..at], node.parse()?, parse_number(counter)?)
+                }
+                None => {
+                    let prev = levels.last().ok_or(LseqError::InvalidEncoding("expected `@` after first index"))?;
+                    (level, prev.node, prev.counter)
+                }
+            };
+            levels.push(Level { index: parse_number(index)?, node, counter });
+        }
+        Ok(Id { levels })
+    }
+}
+
+/// Deserializes an `Id` and checks it with `Id::validate`, so that invalid ids
+/// are rejected during deserialization, e.g.,
+/// `ValidateId(&config).deserialize(&mut deserializer)`.
+pub struct ValidateId<'a>(pub &'a NodeConfig);
+
+impl<'de, 'a> DeserializeSeed<'de> for ValidateId<'a> {
+    type Value = Id;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Id, D::Error> {
+        let id = <Id as serde::Deserialize>::deserialize(deserializer)?;
+        id.validate(self.0).map_err(de::Error::custom)?;
+        Ok(id)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = LseqError;
+
+    fn from_str(s: &str) -> Result<NodeId, LseqError> {
+        parse_number(s).map(NodeId)
+    }
+}
+
+// Only accepts plain decimal digits, so every number has one representation
+// (other than leading zeros).
+fn parse_number<N: FromStr>(s: &str) -> Result<N, LseqError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(LseqError::InvalidEncoding("expected a number"));
+    }
+    // Otherwise the same id would have more than one string.
+    if s.len() > 1 && s.starts_with('0') {
+        return Err(LseqError::InvalidEncoding("leading zero in number"));
+    }
+    s.parse().map_err(|_| LseqError::InvalidEncoding("number out of range"))
+}
+
+fn ensure(condition: bool, msg: &'static str) -> Result<(), LseqError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(LseqError::InvariantBroken(msg))
+    }
+}
+
+fn random_bool(rng: &mut dyn RngCore) -> bool {
     rng.gen()
 }
 
 // Exclusive above and below.
-fn random_range(l: u64, u: u64) -> u64 {
+fn random_range(rng: &mut dyn RngCore, l: u64, u: u64) -> u64 {
     assert!(l + 1 < u, "{} < {}", l + 1, u);
-    let mut rng = thread_rng();
     rng.gen_range(l + 1, u)
 }
 
+fn seeded_rng(seed: u64) -> XorShiftRng {
+    // Spread the seed over all 128 bits of XorShift's state.
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&mix64(seed).to_le_bytes());
+    bytes[8..].copy_from_slice(&mix64(seed.wrapping_add(1)).to_le_bytes());
+    XorShiftRng::from_seed(bytes)
+}
+
+// A cheap, well-mixed and stable hash (the SplitMix64 finaliser).
+fn mix64(x: u64) -> u64 {
+    let mut x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::id;
     use std::collections::BTreeSet;
 
     #[test]
     fn test_random_range() {
+        let mut rng = thread_rng();
         for i in 0..100 {
-            let r = random_range(i, i * 2 + 2);
+            let r = random_range(&mut rng, i, i * 2 + 2);
             assert!(r > i && r < i * 2 + 2);
         }
     }
 
     #[test]
     fn test_id_props() {
-        let a = Id { indices: vec![], node: NodeId(0) };
-        let b = Id { indices: vec![], node: NodeId(2) };
-        let c = Id { indices: vec![5, 32, 100, 2], node: NodeId(2) };
-        let d = Id { indices: vec![5, 32, 100, 2], node: NodeId(2) };
-        let e = Id { indices: vec![5, 32, 100, 2], node: NodeId(3) };
-        let f = Id { indices: vec![5, 32, 100], node: NodeId(2) };
-        let g = Id { indices: vec![4, 40], node: NodeId(0) };
+        let a = id(&[]);
+        let b = id(&[(0, 0, 0)]);
+        let c = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4), (2, 2, 6)]);
+        let d = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4), (2, 2, 6)]);
+        let e = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4), (2, 3, 1)]);
+        let f = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4)]);
+        let g = id(&[(4, 0, 1), (40, 0, 2)]);
+        let h = id(&[(5, 2, 1), (32, 3, 1)]);
+        let i = id(&[(5, 2, 1), (32, 2, 2)]);
 
         // Equality, inequality
         assert!(a == a);
@@ -229,6 +628,7 @@ mod tests {
         assert!(a != b);
         assert!(c != f);
         assert!(d != e);
+        assert!(h != i);
 
         // Ordering
         assert!(a < b);
@@ -238,91 +638,158 @@ mod tests {
         assert!(b < f);
         assert!(c < e);
         assert!(f < c);
+        // Node and counter are compared at each level, before deeper levels.
+        assert!(e < i);
+        assert!(i < h);
+
+        assert_eq!(a.node(), None);
+        assert_eq!(e.node(), Some(NodeId(3)));
     }
 
     #[test]
-    fn test_level_direction() {
-        let mut node = Node::new(NodeId::new(0));
-        let a = node.level_direction(0);
-        let b = node.level_direction(1);
-        let c = node.level_direction(2);
-        let d = node.level_direction(3);
-
-        let a_ = node.level_direction(0);
-        let b_ = node.level_direction(1);
-        let c_ = node.level_direction(2);
-        let d_ = node.level_direction(3);
-
-        assert!(a == a_);
-        assert!(b == b_);
-        assert!(c == c_);
-        assert!(d == d_);
-
-        // Test that eventually we'll get both values.
-        let mut f = false;
-        let mut t = false;
-        let mut i = 0;
-        loop {
-            let a = node.level_direction(i);
-            if a {
-                t = true;
-            } else {
-                f = true;
-            }
+    fn test_validate() {
+        let config = NodeConfig::new();
+        let node = Node::new(NodeId(1));
+        assert_eq!(id(&[(5, 2, 1), (0, 2, 1), (63, 3, 1)]).validate(&config), Ok(()));
+        assert_eq!(id(&[]).validate(&config), Err(LseqError::EmptyId));
+        assert_eq!(id(&[(5, 2, 1), (0, 2, 1)]).validate(&config), Err(LseqError::ZeroLastIndex));
+        assert_eq!(
+            id(&[(5, 2, 1), (32, 2, 1)]).validate(&config),
+            Err(LseqError::IndexOutOfWidth { level: 1, index: 32, width: 32 })
+        );
+        assert_eq!(
+            id(&[(1, 2, 1); 33]).validate(&config),
+            Err(LseqError::DepthLimitExceeded { max_depth: 32 })
+        );
+        assert_eq!(node.begin().validate(&config), Err(LseqError::ZeroLastIndex));
+        assert!(node.end().validate(&config).is_err());
 
-            if t && f {
-                return;
-            }
-
-            i += 1;
+        let mut node = Node::new(NodeId(1));
+        let mut upper = node.end();
+        for _ in 0..100 {
+            upper = node.new_id_before(&upper);
+            assert_eq!(upper.validate(&config), Ok(()));
         }
     }
 
     #[test]
-    fn test_width_at() {
-        let mut node = Node::new(NodeId::new(0));
-        node.initial_width = 5;
-        assert!(node.width_at(0) == 5);
-        assert!(node.width_at(1) == 10);
-        assert!(node.width_at(2) == 20);
-        assert!(node.width_at(3) == 40);
-        assert!(node.width_at(4) == 80);
+    fn test_validate_deserialize() {
+        use bincode::Options;
+
+        let config = NodeConfig::new();
+        let valid = id(&[(5, 2, 1), (3, 2, 1)]);
+        let bytes = bincode::options().serialize(&valid).unwrap();
+        let mut de = bincode::Deserializer::from_slice(&bytes, bincode::options());
+        assert_eq!(ValidateId(&config).deserialize(&mut de).unwrap(), valid);
+
+        let invalid = id(&[(5, 2, 1), (300, 2, 1)]);
+        let bytes = bincode::options().serialize(&invalid).unwrap();
+        let mut de = bincode::Deserializer::from_slice(&bytes, bincode::options());
+        assert!(ValidateId(&config).deserialize(&mut de).is_err());
     }
 
     #[test]
-    fn test_append_index() {
-        let node = Node::new(NodeId::new(0));
+    fn test_inspect() {
+        let a = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4)]);
+        let b = id(&[(5, 2, 1), (32, 2, 1), (7, 3, 1), (1, 3, 1)]);
+        let c = id(&[(5, 2, 1), (32, 3, 1)]);
+
+        assert_eq!(a.depth(), 3);
+        assert_eq!(a.indices().collect::<Vec<_>>(), vec![5, 32, 100]);
+        assert_eq!(a.prefix(2), id(&[(5, 2, 1), (32, 2, 1)]));
+        assert_eq!(a.prefix(0), id(&[]));
+        assert_eq!(a.prefix(5), a);
 
-        let id = Id { indices: vec![], node: NodeId::new(42) };
-        let new_id = node.append_index(&id, 6);
-        assert!(new_id.node == NodeId::new(0));
-        assert!(new_id.indices.len() == 1);
-        assert!(new_id.indices[0] == 6);
+        assert_eq!(a.common_prefix_len(&b), 2);
+        assert_eq!(b.common_prefix_len(&a), 2);
+        assert_eq!(a.common_prefix_len(&a), 3);
+        // Same index, different node.
+        assert_eq!(a.common_prefix_len(&c), 1);
 
-        let id = Id { indices: vec![4], node: NodeId::new(0) };
-        let new_id = node.append_index(&id, 0);
-        assert!(new_id.node == NodeId::new(0));
-        assert!(new_id.indices.len() == 2);
-        assert!(new_id.indices[0] == 4);
-        assert!(new_id.indices[1] == 0);
+        assert!(a.prefix(2).is_ancestor_of(&a));
+        assert!(a.prefix(2).is_ancestor_of(&b));
+        assert!(!a.is_ancestor_of(&a));
+        assert!(!a.is_ancestor_of(&b));
+        assert!(!c.is_ancestor_of(&b));
+        assert!(!a.is_ancestor_of(&a.prefix(2)));
+
+        assert!(b.is_between(&a.prefix(2), &a));
+        assert!(!b.is_between(&a, &a.prefix(2)));
+        assert!(!a.is_between(&a, &c));
+        assert!(!c.is_between(&a, &c));
+        assert!(a.is_between(&b, &c));
     }
 
     #[test]
-    fn test_truncate_and_replace_index() {
-        let node = Node::new(NodeId::new(0));
+    fn test_display() {
+        let a = id(&[(5, 2, 1), (32, 2, 1), (100, 2, 4), (2, 3, 1)]);
+        assert_eq!(a.to_string(), "5@2:1.32.100@2:4.2@3:1");
+        assert_eq!(id(&[(0, 0, 0)]).to_string(), "0@0:0");
+        assert_eq!(id(&[]).to_string(), "");
+        assert_eq!(NodeId(42).to_string(), "42");
 
-        let id = Id { indices: vec![4], node: NodeId::new(0) };
-        let new_id = node.truncate_and_replace_index(&id, 0, 0);
-        assert!(new_id.node == NodeId::new(0));
-        assert!(new_id.indices.len() == 1);
-        assert!(new_id.indices[0] == 0);
+        // Round trip.
+        let mut node = Node::new(NodeId(7));
+        let mut ids = vec![id(&[]), a, node.begin(), node.end()];
+        for _ in 0..100 {
+            let last = ids.last().cloned();
+            ids.push(node.new_id_between(None, last.as_ref()));
+        }
+        for i in ids {
+            assert_eq!(i.to_string().parse::<Id>(), Ok(i));
+        }
+        assert_eq!("42".parse::<NodeId>(), Ok(NodeId(42)));
 
-        let id = Id { indices: vec![4, 5, 3, 2], node: NodeId::new(0) };
-        let new_id = node.truncate_and_replace_index(&id, 1, 0);
-        assert!(new_id.node == NodeId::new(0));
-        assert!(new_id.indices.len() == 2);
-        assert!(new_id.indices[0] == 4);
-        assert!(new_id.indices[1] == 0);
+        // Repeated nodes and counters may be written out in full.
+        assert_eq!("5@2:1.32@2:1".parse::<Id>(), Ok(id(&[(5, 2, 1), (32, 2, 1)])));
+    }
+
+    #[test]
+    fn test_node_id() {
+        assert_eq!(NodeId::from(7u32), NodeId::new(7));
+        assert_eq!(NodeId::from(7u64), NodeId::new(7));
+        assert!(NodeId::from(u32::MAX) < NodeId::from(u64::MAX));
+        assert!(NodeId::from(u64::MAX) < NodeId::from(u128::MAX));
+        assert_eq!(NodeId::from(u64::MAX).value(), u128::from(u64::MAX));
+
+        let a = NodeId::random();
+        assert_ne!(a, NodeId::random());
+        assert_eq!(a.to_string().parse::<NodeId>(), Ok(a));
+        assert_eq!(NodeId::from(u128::MAX).to_string(), "340282366920938463463374607431768211455");
+
+        // Ids from nodes with wide ids are ordered and encoded like any other.
+        let config = NodeConfig::new();
+        let mut x = Node::new(NodeId::from(u128::MAX));
+        let mut y = Node::new(NodeId::from(u64::MAX));
+        let first = x.new_id_between(None, None);
+        let after = y.new_id_after(&first);
+        assert!(first < after);
+        for i in &[first, after] {
+            assert_eq!(Id::decode(&i.encode(&config).unwrap(), &config).as_ref(), Ok(i));
+            assert_eq!(Id::from_sort_key(&i.to_sort_key()).as_ref(), Ok(i));
+            assert_eq!(i.to_string().parse::<Id>().as_ref(), Ok(i));
+        }
+    }
+
+    #[test]
+    fn test_from_str_errors() {
+        for s in &["5", "5@2", "5@2:", "5@:1", "@2:1", "5@2:1.", "5@2:1..6", "+5@2:1", "5@2:1.x", "5@340282366920938463463374607431768211456:1", "5@2:18446744073709551616", "05@2:1", "5@02:1", "5@2:01", "5@2:1.00@2:1"] {
+            assert!(s.parse::<Id>().is_err(), "{}", s);
+        }
+        for s in &["", "-1", "340282366920938463463374607431768211456", "1.5", "007"] {
+            assert!(s.parse::<NodeId>().is_err(), "{}", s);
+        }
+        assert_eq!("0@0:0".parse::<Id>().unwrap(), id(&[(0, 0, 0)]));
+    }
+
+    #[test]
+    fn test_width_at() {
+        let node = Node::with_config(NodeId::new(0), NodeConfig::builder().initial_width(5).build());
+        assert!(node.width_at(0) == 5);
+        assert!(node.width_at(1) == 10);
+        assert!(node.width_at(2) == 20);
+        assert!(node.width_at(3) == 40);
+        assert!(node.width_at(4) == 80);
     }
 
     #[test]
@@ -332,7 +799,7 @@ mod tests {
             for j in (i+2)..20 {
                 for depth in 0..10 {
                     for _ in 0..1000 {
-                        let new_index = node.pick_index(depth, i, j);
+                        let new_index = node.pick_index(depth, i, j).unwrap();
                         assert!(new_index > i && new_index < j);
                     }
                 }
@@ -368,9 +835,7 @@ mod tests {
                 let mut index_0 = rng.gen_range(0, results.len());
                 let mut index_1 = rng.gen_range(0, results.len());
                 if index_0 > index_1 {
-                    let temp = index_0;
-                    index_0 = index_1;
-                    index_1 = temp;
+                    std::mem::swap(&mut index_0, &mut index_1);
                 }
                 let id_0 = results.iter().nth(index_0).unwrap();
                 let id_1 = results.iter().nth(index_1).unwrap();
@@ -416,4 +881,319 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_seed() {
+        fn ids(seed: u64) -> Vec<Id> {
+            let mut node = Node::new(NodeId::new(0));
+            node.set_seed(seed);
+            let mut ids = vec![node.begin()];
+            for i in 0..200 {
+                let lower = ids[i / 2].clone();
+                let upper = ids[ids.len() - 1].clone();
+                let new = node.new_id_with_bounds(&lower, &upper);
+                ids.push(new);
+                ids.sort();
+            }
+            ids
+        }
+
+        assert_eq!(ids(42), ids(42));
+        assert_ne!(ids(42), ids(43));
+    }
+
+    #[test]
+    fn test_node_serde() {
+        let mut a = Node::new(NodeId(1));
+        let mut ids = vec![a.new_id_between(None, None)];
+        for _ in 0..50 {
+            let last = ids.last().cloned();
+            ids.push(a.new_id_between(last.as_ref(), None));
+        }
+
+        let mut b: Node = bincode::deserialize(&bincode::serialize(&a).unwrap()).unwrap();
+        assert_eq!(b.id, a.id);
+        assert_eq!(b.config(), a.config());
+
+        // The restored node allocates exactly as the original would.
+        a.set_seed(42);
+        b.set_seed(42);
+        let mut lower = ids[0].clone();
+        for _ in 0..50 {
+            let id = a.new_id_between(Some(&lower), Some(&ids[1]));
+            assert_eq!(b.new_id_between(Some(&lower), Some(&ids[1])), id);
+            assert!(!ids.contains(&id));
+            lower = id;
+        }
+    }
+
+    #[test]
+    fn test_try_new_id_errors() {
+        let mut node = Node::new(NodeId::new(0));
+        let empty = id(&[]);
+        let a = id(&[(3, 0, 1)]);
+        let b = id(&[(5, 0, 2), (2, 0, 2)]);
+        let wide = id(&[(5, 0, 3), (32, 0, 3)]);
+        let deep = id(&[(1, 0, 4); 33]);
+
+        assert_eq!(node.try_new_id_with_bounds(&empty, &a), Err(LseqError::EmptyId));
+        assert_eq!(node.try_new_id_with_bounds(&a, &empty), Err(LseqError::EmptyId));
+        assert_eq!(node.try_new_id_with_bounds(&b, &a), Err(LseqError::BoundsReversed));
+        assert_eq!(
+            node.try_new_id_with_bounds(&a, &wide),
+            Err(LseqError::IndexOutOfWidth { level: 1, index: 32, width: 32 })
+        );
+        assert_eq!(
+            node.try_new_id_with_bounds(&a, &deep),
+            Err(LseqError::DepthLimitExceeded { max_depth: 32 })
+        );
+        assert!(node.try_new_id_with_bounds(&a, &b).is_ok());
+
+        // Untrusted bounds are validated like ids, rather than breaking an
+        // invariant of the allocator.
+        let zero = id(&[(1, 0, 2), (0, 0, 0)]);
+        assert_eq!(node.try_new_id_between(None, Some(&zero)), Err(LseqError::ZeroLastIndex));
+        assert_eq!(node.try_new_id_between(Some(&zero), None), Err(LseqError::ZeroLastIndex));
+    }
+
+    #[test]
+    fn test_try_new_id_no_panic() {
+        // Arbitrary (but in width) bounds must never cause a panic.
+        let mut rng = thread_rng();
+        let mut node = Node::new(NodeId::new(1));
+        for _ in 0..10000 {
+            let mut random_id = || {
+                let depth = rng.gen_range(1, 4);
+                Id {
+                    levels: (0..depth)
+                        .map(|l| Level {
+                            index: rng.gen_range(0, node.width_at(l).min(4)),
+                            node: NodeId::from(rng.gen_range(0u32, 3)),
+                            counter: rng.gen_range(0, 2),
+                        })
+                        .collect(),
+                }
+            };
+            let mut lower = random_id();
+            let mut upper = random_id();
+            if lower > upper {
+                std::mem::swap(&mut lower, &mut upper);
+            }
+            match node.try_new_id_with_bounds(&lower, &upper) {
+                Ok(new) => {
+                    assert!(new > lower);
+                    assert!(new < upper || lower == upper);
+                }
+                Err(e) => assert!(!matches!(e, LseqError::InvariantBroken(_)), "{} ({}, {})", e, lower, upper),
+            }
+        }
+    }
+
+    #[test]
+    fn test_begin_end() {
+        let mut node = Node::new(NodeId::new(0));
+        let begin = node.begin();
+        let end = node.end();
+        assert!(begin < end);
+
+        let mut ids = vec![];
+        for _ in 0..100 {
+            ids.push(node.new_id_between(None, None));
+        }
+        for _ in 0..100 {
+            let last = ids[ids.len() - 1].clone();
+            ids.push(node.new_id_after(&last));
+            let first = ids[0].clone();
+            ids.push(node.new_id_before(&first));
+        }
+        for id in &ids {
+            assert!(&begin < id && id < &end);
+        }
+
+        assert_eq!(node.try_new_id_between(Some(&end), None), Err(LseqError::BoundsReversed));
+        assert_eq!(node.try_new_id_with_bounds(&end, &end), Err(LseqError::BoundsReversed));
+        assert_eq!(node.try_new_id_between(None, Some(&begin)), Err(LseqError::BoundsReversed));
+        assert_eq!(node.try_new_id_between(Some(&ids[0]), Some(&ids[0])), Err(LseqError::BoundsReversed));
+    }
+
+    #[test]
+    fn test_id_after_before() {
+        for _ in 0..100 {
+            let mut node = Node::new(NodeId::new(0));
+            let mut first = node.new_id_between(None, None);
+            let mut last = first.clone();
+            for _ in 0..100 {
+                let new = node.new_id_after(&last);
+                assert!(new > last);
+                last = new;
+
+                let new = node.new_id_before(&first);
+                assert!(new < first);
+                first = new;
+            }
+        }
+    }
+
+    #[test]
+    fn test_id_between() {
+        let mut node = Node::new(NodeId::new(0));
+        let a = node.new_id_between(None, None);
+        let b = node.new_id_after(&a);
+        let mut lower = a.clone();
+        for _ in 0..100 {
+            let new = node.new_id_between(Some(&lower), Some(&b));
+            assert!(new > lower && new < b);
+            lower = new;
+        }
+    }
+
+    #[test]
+    fn test_ids_between() {
+        let mut node = Node::new(NodeId::new(0));
+        assert!(node.new_ids_between(None, None, 0).is_empty());
+
+        // Shallowest level with room.
+        let ids = node.new_ids_between(None, None, 15);
+        assert!(ids.iter().all(|id| id.depth() == 1));
+        let ids = node.new_ids_between(None, None, 16);
+        assert!(ids.iter().all(|id| id.depth() == 2));
+
+        // Evenly spread.
+        let ids = node.new_ids_between(None, None, 3);
+        assert_eq!(ids.iter().map(|id| id.levels[0].index).collect::<Vec<_>>(), vec![4, 8, 12]);
+
+        for _ in 0..100 {
+            let a = node.new_id_between(None, None);
+            let b = node.new_id_after(&a);
+            let lower = node.new_id_between(Some(&a), Some(&b));
+            for n in &[1, 10, 100, 1000] {
+                let ids = node.new_ids_between(Some(&lower), Some(&b), *n);
+                assert_eq!(ids.len(), *n);
+                assert!(lower < ids[0]);
+                assert!(ids[ids.len() - 1] < b);
+                for pair in ids.windows(2) {
+                    assert!(pair[0] < pair[1]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_id_deep() {
+        // Always inserting directly after the same id with a boundary of 1 is
+        // the worst case, each new id is about one level deeper than the last.
+        let config = NodeConfig::builder().boundary(1).max_depth(200).build();
+        let mut node = Node::with_strategy(NodeId::new(1), config.clone(), BoundaryPlus);
+        let first = node.new_id_between(None, None);
+        let mut ids = vec![first.clone()];
+        let mut upper = node.end();
+        while upper.depth() <= 100 {
+            upper = node.new_id_between(Some(&first), Some(&upper));
+            assert_eq!(upper.validate(&config), Ok(()));
+            assert!(upper.indices().all(|i| i < NodeConfig::MAX_WIDTH));
+            ids.insert(1, upper.clone());
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+
+        // Allocating between the deep ids still works.
+        let deep = node.new_id_between(Some(&ids[1]), Some(&ids[2]));
+        assert!(deep.is_between(&ids[1], &ids[2]));
+        assert!(deep.depth() > 64);
+        let bytes = deep.encode(&config).unwrap();
+        assert_eq!(Id::decode(&bytes, &config), Ok(deep.clone()));
+        assert_eq!(Id::from_sort_key(&deep.to_sort_key()), Ok(deep));
+    }
+
+    #[test]
+    fn test_ids_between_depth() {
+        // Bulk allocation gives much shallower ids than chaining.
+        let mut node = Node::new(NodeId::new(0));
+        let first = node.new_id_between(None, None);
+        let ids = node.new_ids_between(Some(&first), None, 1000);
+        assert!(ids.iter().all(|id| id.depth() <= first.depth() + 6));
+
+        let config = NodeConfig::builder().max_depth(2).build();
+        let mut node = Node::with_config(NodeId::new(0), config);
+        assert_eq!(
+            node.try_new_ids_between(None, None, 1000),
+            Err(LseqError::DepthLimitExceeded { max_depth: 2 })
+        );
+        assert_eq!(node.try_new_ids_between(Some(&node.end()), None, 1), Err(LseqError::BoundsReversed));
+    }
+
+    #[test]
+    fn test_id_collision() {
+        // Two nodes which allocate the same indices.
+        let mut a = Node::new(NodeId::new(1));
+        let mut b = Node::new(NodeId::new(2));
+        let mut c = Node::new(NodeId::new(3));
+        a.set_seed(0);
+        b.set_seed(0);
+
+        let mut ids = BTreeSet::new();
+        let id_a = a.new_id_between(None, None);
+        let id_b = b.new_id_between(None, None);
+        assert_eq!(id_a.levels[0].index, id_b.levels[0].index);
+        assert!(id_a < id_b);
+        ids.insert(id_a.clone());
+        ids.insert(id_b.clone());
+
+        // A third node can always insert between them.
+        let mut lower = id_a.clone();
+        for _ in 0..100 {
+            let new = c.new_id_between(Some(&lower), Some(&id_b));
+            assert!(new > lower && new < id_b);
+            assert!(ids.insert(new.clone()));
+            lower = new;
+        }
+        let mut upper = id_b.clone();
+        for _ in 0..100 {
+            let new = c.new_id_between(Some(&id_a), Some(&upper));
+            assert!(new > id_a && new < upper);
+            assert!(ids.insert(new.clone()));
+            upper = new;
+        }
+    }
+
+    #[test]
+    fn test_id_collision_same_node() {
+        // With a width of 2, there is only one index available, so
+        // allocations only differ by their counters.
+        let config = NodeConfig::builder().initial_width(2).growth(WidthGrowth::Constant).build();
+        let mut node = Node::with_config(NodeId::new(0), config);
+        let first = node.new_id_between(None, None);
+        let second = node.new_id_between(None, None);
+        assert_ne!(first, second);
+        assert_eq!(first.levels[0].index, second.levels[0].index);
+
+        let between = node.new_id_between(Some(&first), Some(&second));
+        assert!(first < between && between < second);
+    }
+
+    #[test]
+    fn test_id_random_concurrent() {
+        // Several nodes allocating between a shared, growing, set of ids, with
+        // the same random choices and a small width, so many ids share indices.
+        let mut rng = thread_rng();
+        let config = NodeConfig::builder().initial_width(4).boundary(1).build();
+        let mut nodes: Vec<_> = (0..3).map(|i| {
+            let mut node = Node::with_config(NodeId::new(i), config.clone());
+            node.set_seed(42);
+            node
+        }).collect();
+
+        let mut results = BTreeSet::new();
+        results.insert(nodes[0].begin());
+        results.insert(nodes[0].end());
+        for _ in 0..500 {
+            let index = rng.gen_range(0, results.len() - 1);
+            let lower = results.iter().nth(index).unwrap().clone();
+            let upper = results.iter().nth(index + 1).unwrap().clone();
+            for node in &mut nodes {
+                let new = node.new_id_between(Some(&lower), Some(&upper));
+                assert!(new > lower && new < upper);
+                assert!(results.insert(new));
+            }
+        }
+    }
 }
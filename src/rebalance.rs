@@ -0,0 +1,433 @@
+use crate::version::Dot;
+use crate::{Id, IdBlock, LseqError, NodeConfig, NodeId};
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+
+/// Replaces the ids of a range of elements with compact, evenly spaced ids,
+/// created by `LSeq::rebalance`. Applying a rebalance ends an epoch.
+///
+/// Every id strictly between the bounds of the range is renamed, whether or
+/// not the rebalancing replica knew about it, by `Rebalance::translate`. The
+/// ids of the elements in the range when it was rebalanced map to the
+/// corresponding new ids. Any other id (e.g., inserted concurrently with the
+/// rebalance) is moved beneath a new id between the new ids of its old
+/// neighbours, keeping its order, and is dropped if that would make it deeper
+/// than `NodeConfig::max_depth`. Since translation only depends on the id,
+/// ops from earlier epochs can be translated whenever they arrive.
+///
+/// A rebalance records the rebalance which ended the previous epoch (its
+/// base), so that concurrent rebalances of the same epoch can be told apart.
+/// The renaming can be undone by `Rebalance::untranslate` if a concurrent
+/// rebalance wins, see `LSeq::rebalance`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Rebalance {
+    epoch: u64,
+    // The op of the rebalance which ended the previous epoch, `None` in the
+    // first epoch.
+    base: Option<Dot>,
+    // The ids either side of the range, `None` for the ends of the sequence.
+    lower: Option<Id>,
+    upper: Option<Id>,
+    // The ids of the elements in the range, in order.
+    old: Vec<IdBlock>,
+    // One more id than `old`, `new.get(i + 1)` replaces the `i`th old id.
+    // Ids between the old ids are moved beneath `new.get(i)`, where `i` is
+    // the number of old ids less than them.
+    new: IdBlock,
+    // The number of ids in `old` before each block.
+    #[serde(skip)]
+    offsets: Vec<usize>,
+}
+
+// Where an id is relative to a rebalance.
+enum Location {
+    // Not between the bounds, so not changed.
+    Outside,
+    // The `i`th old id.
+    Old(usize),
+    // Between the old ids, `i` of which are less than it.
+    Between(usize),
+}
+
+impl Rebalance {
+    pub(crate) fn new(epoch: u64, base: Option<Dot>, lower: Option<Id>, upper: Option<Id>, old: Vec<IdBlock>, new: IdBlock) -> Rebalance {
+        debug_assert_eq!(old.iter().map(IdBlock::len).sum::<usize>() + 1, new.len());
+        debug_assert_eq!(epoch == 0, base.is_none());
+        let offsets = offsets(&old);
+        Rebalance { epoch, base, lower, upper, old, new, offsets }
+    }
+
+    /// The epoch which this rebalance ends. Ops created after it have the
+    /// next epoch.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The origin and seq of the op of the rebalance which ended the
+    /// previous epoch, `None` in the first epoch.
+    pub fn base(&self) -> Option<(NodeId, u64)> {
+        self.base
+    }
+
+    /// The number of elements which were given new ids.
+    pub fn len(&self) -> usize {
+        self.new.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The id which replaces `id`, which is `id` itself if it is outside
+    /// the rebalanced range, or `None` if `id` is dropped (see
+    /// `Rebalance`). `config` must be the config of the replica which
+    /// created the rebalance.
+    pub fn translate(&self, id: &Id, config: &NodeConfig) -> Option<Id> {
+        match self.locate(id) {
+            Location::Outside => Some(id.clone()),
+            Location::Old(i) => self.new.get(i + 1),
+            Location::Between(i) => {
+                let mut levels = self.new.get(i).unwrap().levels;
+                if levels.len() + id.depth() > config.max_depth() {
+                    return None;
+                }
+                // Widths never shrink, so the levels of `id` are still valid
+                // further down the tree.
+                levels.extend(id.levels.iter().cloned());
+                Some(Id { levels })
+            }
+        }
+    }
+
+    /// The id which `id`, an id from after this rebalance, replaced, i.e.,
+    /// the inverse of `translate` for the ids it renamed. Other ids (e.g.,
+    /// allocated after the rebalance) are unchanged: they are unique, so
+    /// don't clash with any other id, but may end up in a different place
+    /// relative to the ids around them. `config` must be the config of the
+    /// replica which created the rebalance.
+    pub fn untranslate(&self, id: &Id, config: &NodeConfig) -> Id {
+        self.unlocate(id, config).map_or_else(|| id.clone(), |(id, _)| id)
+    }
+
+    /// Translate the ids of `ids`. Returns the translated ids as blocks, with
+    /// the offset in `ids` of the first id of each, leaving out dropped ids.
+    /// Consecutive old ids stay together.
+    pub(crate) fn translate_block(&self, ids: &IdBlock, config: &NodeConfig) -> Vec<(usize, IdBlock)> {
+        if !self.contains(ids.first()) && !self.contains(&ids.last()) && !self.spans(ids) {
+            return vec![(0, ids.clone())];
+        }
+
+        let mut result: Vec<(usize, IdBlock)> = Vec::new();
+        // If the last id added to `result` was an old id, the position in
+        // `old` of the next one.
+        let mut next_old = None;
+        for (offset, id) in ids.iter().enumerate() {
+            if let Location::Old(i) = self.locate(&id) {
+                match result.last_mut() {
+                    Some((start, block)) if next_old == Some(i) && *start + block.len() == offset => {
+                        *block = IdBlock::new(block.first().clone(), self.new.step(), block.len() + 1);
+                    }
+                    _ => result.push((offset, IdBlock::single(self.new.get(i + 1).unwrap()))),
+                }
+                next_old = Some(i + 1);
+            } else {
+                next_old = None;
+                if let Some(id) = self.translate(&id, config) {
+                    result.push((offset, IdBlock::single(id)));
+                }
+            }
+        }
+        result
+    }
+
+    /// Like `translate_block`, but for `untranslate`, so no ids are dropped.
+    pub(crate) fn untranslate_block(&self, ids: &IdBlock, config: &NodeConfig) -> Vec<(usize, IdBlock)> {
+        if !self.contains(ids.first()) && !self.contains(&ids.last()) && !self.spans(ids) {
+            return vec![(0, ids.clone())];
+        }
+
+        let mut result: Vec<(usize, IdBlock)> = Vec::new();
+        // If the last id added to `result` was an old id, the position in
+        // `old` of the next one.
+        let mut next_old = None;
+        for (offset, id) in ids.iter().enumerate() {
+            match self.unlocate(&id, config) {
+                Some((old, Location::Old(i))) => {
+                    match result.last_mut() {
+                        // Only old ids from the same block are consecutive.
+                        Some((start, block))
+                            if next_old == Some(i) && *start + block.len() == offset && self.offsets.binary_search(&i).is_err() =>
+                        {
+                            let step = self.old[self.offsets.partition_point(|&start| start <= i) - 1].step();
+                            *block = IdBlock::new(block.first().clone(), step, block.len() + 1);
+                        }
+                        _ => result.push((offset, IdBlock::single(old))),
+                    }
+                    next_old = Some(i + 1);
+                }
+                other => {
+                    next_old = None;
+                    result.push((offset, IdBlock::single(other.map_or(id, |(id, _)| id))));
+                }
+            }
+        }
+        result
+    }
+
+    /// The bounds of the rebalanced range, exclusive.
+    pub(crate) fn bounds(&self) -> (Option<&Id>, Option<&Id>) {
+        (self.lower.as_ref(), self.upper.as_ref())
+    }
+
+    /// The rebalances with their ops, ordered by epoch, then origin and seq,
+    /// so that each comes after its base.
+    pub(crate) fn sort(rebalances: &mut [(Dot, Rebalance)]) {
+        rebalances.sort_by_key(|(dot, rebalance)| (rebalance.epoch, *dot));
+    }
+
+    /// Check that every id is valid for `config`, see `Id::validate`.
+    pub(crate) fn validate(&self, config: &NodeConfig) -> Result<(), LseqError> {
+        self.lower.iter().chain(&self.upper).try_for_each(|id| id.validate(config))?;
+        self.old.iter().try_for_each(|ids| ids.validate(config))?;
+        self.new.validate(config)
+    }
+
+    // Whether `id` is strictly between the bounds.
+    fn contains(&self, id: &Id) -> bool {
+        self.lower.as_ref().is_none_or(|lower| lower < id) && self.upper.as_ref().is_none_or(|upper| id < upper)
+    }
+
+    // Whether the bounds are between the first and last ids of `ids`.
+    fn spans(&self, ids: &IdBlock) -> bool {
+        self.lower.as_ref().is_some_and(|lower| ids.first() < lower && *lower < ids.last())
+    }
+
+    // The `i`th old id.
+    fn old_id(&self, i: usize) -> Id {
+        let block = self.offsets.partition_point(|&start| start <= i) - 1;
+        self.old[block].get(i - self.offsets[block]).unwrap()
+    }
+
+    // The id which `id` replaced, and where that id was, or `None` if this
+    // rebalance didn't rename `id`.
+    fn unlocate(&self, id: &Id, config: &NodeConfig) -> Option<(Id, Location)> {
+        if !self.contains(id) {
+            return None;
+        }
+        // The anchor isn't an element, so isn't a translation.
+        match self.new.position(id) {
+            Ok(0) => return None,
+            Ok(i) => return Some((self.old_id(i - 1), Location::Old(i - 1))),
+            Err(_) => {}
+        }
+        // A translation of an id between the old ids is beneath the new id
+        // before it, see `translate`.
+        let depth = self.new.first().depth();
+        if id.depth() <= depth {
+            return None;
+        }
+        let i = self.new.position(&Id { levels: id.levels[..depth].to_vec() }).ok()?;
+        let old = Id { levels: id.levels[depth..].to_vec() };
+        // An id allocated beneath a new id needn't be valid higher up.
+        if old.validate(config).is_err() {
+            return None;
+        }
+        match self.locate(&old) {
+            Location::Between(k) if k == i => Some((old, Location::Between(k))),
+            _ => None,
+        }
+    }
+
+    fn locate(&self, id: &Id) -> Location {
+        if !self.contains(id) {
+            return Location::Outside;
+        }
+        // The last block starting at or before `id`.
+        let block = self.old.partition_point(|ids| ids.first() <= id);
+        if block == 0 {
+            return Location::Between(0);
+        }
+        match self.old[block - 1].position(id) {
+            Ok(i) => Location::Old(self.offsets[block - 1] + i),
+            Err(i) => Location::Between(self.offsets[block - 1] + i),
+        }
+    }
+}
+
+fn offsets(old: &[IdBlock]) -> Vec<usize> {
+    old.iter()
+        .scan(0, |offset, ids| {
+            let start = *offset;
+            *offset += ids.len();
+            Some(start)
+        })
+        .collect()
+}
+
+// The first and last ids of each block.
+fn ends<'a>(blocks: impl IntoIterator<Item = &'a IdBlock>) -> Vec<Id> {
+    let mut ids = Vec::new();
+    for block in blocks {
+        ids.push(block.first().clone());
+        if block.len() > 1 {
+            ids.push(block.last());
+        }
+    }
+    ids
+}
+
+/// Checks that the rebalance is consistent, but not that its ids are valid
+/// for any particular config, see `LSeq::apply_remote`.
+impl<'de> Deserialize<'de> for Rebalance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rebalance, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Rebalance")]
+        struct Fields {
+            epoch: u64,
+            base: Option<Dot>,
+            lower: Option<Id>,
+            upper: Option<Id>,
+            old: Vec<IdBlock>,
+            new: IdBlock,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        if (f.epoch == 0) != f.base.is_none() {
+            return Err(de::Error::custom("rebalance base doesn't match its epoch"));
+        }
+        f.new.check().map_err(de::Error::custom)?;
+        for block in &f.old {
+            block.check().map_err(de::Error::custom)?;
+        }
+        // The old ids and the new ids must be between the bounds, and the old
+        // ids in order.
+        let in_order = |ids: Vec<Id>| {
+            let ids: Vec<&Id> = f.lower.iter().chain(&ids).chain(&f.upper).collect();
+            ids.windows(2).all(|pair| pair[0] < pair[1])
+        };
+        if !in_order(ends(&f.old)) || !in_order(ends(Some(&f.new))) {
+            return Err(de::Error::custom("rebalance ids out of order"));
+        }
+        if f.old.iter().map(IdBlock::len).sum::<usize>() + 1 != f.new.len() {
+            return Err(de::Error::custom("wrong number of new ids"));
+        }
+        Ok(Rebalance::new(f.epoch, f.base, f.lower, f.upper, f.old, f.new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Level, Node, NodeId};
+
+    #[test]
+    fn test_translate() {
+        let config = NodeConfig::new();
+        let mut node = Node::new(NodeId::new(1));
+        let old = node.new_block_between(None, None, 4);
+        let lower = old.get(0).unwrap();
+        let upper = old.get(3).unwrap();
+        let between = node.new_id_between(old.get(1).as_ref(), old.get(2).as_ref());
+        let before = node.new_id_between(Some(&lower), old.get(1).as_ref());
+
+        let mut middle = old.clone();
+        let mut rest = middle.split_off(1);
+        rest.split_off(2);
+        let new = node.new_block_between(Some(&lower), Some(&upper), 3);
+        let rebalance = Rebalance::new(0, None, Some(lower.clone()), Some(upper.clone()), vec![rest], new.clone());
+        assert_eq!(rebalance.len(), 2);
+
+        let translate = |id: &Id| rebalance.translate(id, &config).unwrap();
+        assert_eq!(translate(&lower), lower);
+        assert_eq!(translate(&upper), upper);
+        assert_eq!(translate(&old.get(1).unwrap()), new.get(1).unwrap());
+        assert_eq!(translate(&old.get(2).unwrap()), new.get(2).unwrap());
+        assert!(new.get(0).unwrap().is_ancestor_of(&translate(&before)));
+        assert!(new.get(1).unwrap().is_ancestor_of(&translate(&between)));
+
+        // Order is preserved.
+        let mut ids = [lower.clone(), before, old.get(1).unwrap(), between.clone(), old.get(2).unwrap(), upper.clone()];
+        ids.sort();
+        let translated: Vec<_> = ids.iter().map(translate).collect();
+        assert!(translated.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Translated ids which would be too deep are dropped.
+        let shallow = NodeConfig::builder().max_depth(new.first().depth()).build();
+        assert_eq!(rebalance.translate(&between, &shallow), None);
+        assert!(rebalance.translate(&old.get(1).unwrap(), &shallow).is_some());
+    }
+
+    #[test]
+    fn test_translate_block() {
+        let config = NodeConfig::new();
+        let mut node = Node::new(NodeId::new(1));
+        let old = node.new_block_between(None, None, 5);
+        let new = node.new_block_between(None, None, 6);
+        let rebalance = Rebalance::new(0, None, None, None, vec![old.clone()], new.clone());
+
+        let mut expected = new.clone();
+        let expected = expected.split_off(1);
+        assert_eq!(rebalance.translate_block(&old, &config), vec![(0, expected)]);
+
+        let mut parts = old.clone();
+        let last = parts.split_off(4);
+        let translated = rebalance.translate_block(&last, &config);
+        assert_eq!(translated, vec![(0, IdBlock::single(new.get(5).unwrap()))]);
+
+        // A block from before the rebalance which interleaves with the old
+        // ids.
+        let other = node.new_block_between(old.get(0).as_ref(), old.get(1).as_ref(), 3);
+        let translated = rebalance.translate_block(&other, &config);
+        assert_eq!(translated.len(), 3);
+        assert!(translated.iter().all(|(_, ids)| new.get(1).unwrap().is_ancestor_of(ids.first())));
+    }
+
+    #[test]
+    fn test_untranslate() {
+        let config = NodeConfig::new();
+        let mut node = Node::new(NodeId::new(1));
+        let first = node.new_block_between(None, None, 2);
+        let second = node.new_block_between(Some(&first.last()), None, 2);
+        let between = node.new_id_between(first.get(1).as_ref(), second.get(0).as_ref());
+        let before = node.new_id_between(None, first.get(0).as_ref());
+        let new = node.new_block_between(None, None, 5);
+        let rebalance = Rebalance::new(0, None, None, None, vec![first.clone(), second.clone()], new.clone());
+
+        for id in first.iter().chain(second.iter()).chain(vec![between.clone(), before]) {
+            assert_eq!(rebalance.untranslate(&rebalance.translate(&id, &config).unwrap(), &config), id);
+        }
+        // Ids allocated after the rebalance are unchanged.
+        let fresh = node.new_id_between(new.get(2).as_ref(), new.get(3).as_ref());
+        assert_eq!(rebalance.untranslate(&fresh, &config), fresh);
+        assert_eq!(rebalance.untranslate(&new.get(0).unwrap(), &config), new.get(0).unwrap());
+        // Including ones beneath a new id which would be too wide higher up.
+        let mut tail = between.clone();
+        tail.levels.push(Level { index: config.width_at(tail.depth()), node: NodeId::new(2), counter: 0 });
+        let mut wide = new.get(2).unwrap();
+        wide.levels.extend(tail.levels);
+        assert!(wide.validate(&config).is_ok());
+        assert_eq!(rebalance.untranslate(&wide, &config), wide);
+
+        // Old ids from the same block stay together.
+        let mut renamed = new.clone();
+        let renamed = renamed.split_off(1);
+        assert_eq!(rebalance.untranslate_block(&renamed, &config), vec![(0, first), (2, second)]);
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut node = Node::new(NodeId::new(1));
+        let old = node.new_block_between(None, None, 3);
+        let new = node.new_block_between(None, None, 4);
+        let rebalance = Rebalance::new(2, Some((NodeId::new(1), 5)), None, None, vec![old.clone()], new.clone());
+        let bytes = bincode::serialize(&rebalance).unwrap();
+        assert_eq!(bincode::deserialize::<Rebalance>(&bytes).unwrap(), rebalance);
+
+        let mut short = new.clone();
+        short.split_off(3);
+        let invalid = Rebalance { new: short, ..rebalance.clone() };
+        assert!(bincode::deserialize::<Rebalance>(&bincode::serialize(&invalid).unwrap()).is_err());
+        let invalid = Rebalance { lower: new.get(2), ..rebalance };
+        assert!(bincode::deserialize::<Rebalance>(&bincode::serialize(&invalid).unwrap()).is_err());
+    }
+}
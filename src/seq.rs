@@ -0,0 +1,1914 @@
+use crate::contents::{Contents, ContentsFields};
+use crate::version::{Dot, Dots};
+use crate::{AllocationStrategy, Delta, Id, IdBlock, LseqError, Node, NodeConfig, NodeId, RandomMix, Rebalance, VersionVector};
+use rand::RngCore;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// A replicated sequence of `T`s.
+///
+/// Each element is tagged with a unique `Id`, elements are kept in `Id` order.
+/// Local edits return an `Op` which should be sent to the other replicas and
+/// applied there with `LSeq::apply_remote`.
+///
+/// Replicas with the same config which have applied the same set of ops have
+/// the same contents, whatever order the ops were applied in and however many
+/// times each op was applied. To make this possible, the ids of removed
+/// elements are remembered (even if the element was never inserted, since
+/// the op inserting it may arrive later), so that inserting them again is a
+/// no-op. This costs memory for every removed element.
+///
+/// Every op is also kept in a log, so that a replica which has missed some
+/// ops can catch up, see `LSeq::missing_ops_since`.
+///
+/// Alternatively, replicas can exchange their whole state: an `LSeq` can be
+/// serialized (including its removed ids and log), and two states can be
+/// combined with `LSeq::merge`. Or they can exchange just the changes to
+/// their state which the other has not seen, see `LSeq::delta_since`.
+///
+/// Elements inserted together are stored together as a run with a single
+/// `IdBlock` (until they are split up by later edits). Runs are stored in a
+/// balanced tree, so finding an element by index or by id, inserting and
+/// removing are all O(log n).
+///
+/// Ids get longer as more elements are inserted in the same place, and are
+/// only made shorter again by `LSeq::rebalance`, which gives a range of
+/// elements new ids. Each rebalance starts a new epoch, ops record the epoch
+/// they were created in so that ids from earlier epochs can be translated.
+pub struct LSeq<T, S = RandomMix> {
+    node: Node<S>,
+    contents: Contents<T>,
+    // The ops whose changes are included in `contents`.
+    seen: Dots,
+    // The ops created or applied, by origin and seq. Ops received in deltas
+    // are not logged.
+    log: BTreeMap<NodeId, BTreeMap<u64, Op<T>>>,
+    // The ops in `seen` with no gaps before them.
+    version: VersionVector,
+    // The rebalance which ended each epoch, and the op which made it, see
+    // `LSeq::add_rebalance`.
+    rebalances: Vec<(Dot, Rebalance)>,
+    // The elements and removed ids which each of `rebalances` dropped, with
+    // their ids from before it, so that they can be restored if it is undone.
+    dropped: Vec<Contents<T>>,
+    // The other rebalances, which lost to a concurrent rebalance or are based
+    // on one which did.
+    losers: BTreeMap<Dot, Rebalance>,
+    // Ops based on rebalances which haven't arrived yet, at most
+    // `MAX_PENDING`. Not serialized, since they are not included in
+    // `version` other replicas will send them again, but merged so that
+    // merging commutes.
+    pending: Vec<Op<T>>,
+}
+
+// The most ops an `LSeq` keeps while they wait for a rebalance.
+const MAX_PENDING: usize = 1024;
+
+/// A replicated edit to an `LSeq`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Op<T> {
+    /// The `NodeConfig::fingerprint` of the replica which created the op.
+    pub config: u64,
+    /// The replica which created the op.
+    pub origin: NodeId,
+    /// The number of ops created by `origin` up to and including this one,
+    /// see `VersionVector`.
+    pub seq: u64,
+    /// The number of rebalances `origin` had applied when it created the op,
+    /// see `LSeq::rebalance`.
+    pub epoch: u64,
+    /// The origin and seq of the rebalance which started `epoch` on
+    /// `origin`, `None` in the first epoch.
+    pub base: Option<(NodeId, u64)>,
+    pub kind: OpKind<T>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum OpKind<T> {
+    Insert(Id, T),
+    /// Insert consecutive values, the `i`th value has the `i`th id of the
+    /// block.
+    InsertBlock(IdBlock, Vec<T>),
+    Remove(Id),
+    /// Several ops from the same replica, applied in order. Can't contain a
+    /// `Rebalance`.
+    Batch(Vec<OpKind<T>>),
+    /// Give elements new ids and end the op's epoch.
+    Rebalance(Rebalance),
+}
+
+impl<T: Clone> LSeq<T> {
+    pub fn new(id: NodeId) -> LSeq<T> {
+        LSeq::with_config(id, NodeConfig::new())
+    }
+
+    /// All replicas of a sequence must be created with the same config.
+    pub fn with_config(id: NodeId, config: NodeConfig) -> LSeq<T> {
+        LSeq::with_strategy(id, config, RandomMix::new())
+    }
+}
+
+impl<T: Clone, S: AllocationStrategy> LSeq<T, S> {
+    /// Replicas may use different strategies, but must use the same config.
+    pub fn with_strategy(id: NodeId, config: NodeConfig, strategy: S) -> LSeq<T, S> {
+        LSeq {
+            node: Node::with_strategy(id, config, strategy),
+            contents: Contents::new(),
+            seen: Dots::new(),
+            log: BTreeMap::new(),
+            version: VersionVector::new(),
+            rebalances: Vec::new(),
+            dropped: Vec::new(),
+            losers: BTreeMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node.id
+    }
+
+    /// See `Node::set_rng`.
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) {
+        self.node.set_rng(rng);
+    }
+
+    /// See `Node::set_seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.node.set_seed(seed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.contents.get(index)
+    }
+
+    /// The element with id `id`.
+    pub fn get_by_id(&self, id: &Id) -> Option<&T> {
+        self.contents.get_by_id(id)
+    }
+
+    /// The id of the element at `index`.
+    pub fn id_at(&self, index: usize) -> Option<Id> {
+        self.contents.id_at(index)
+    }
+
+    /// The index of the element with id `id`.
+    pub fn index_of(&self, id: &Id) -> Option<usize> {
+        self.contents.index_of(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.contents.iter()
+    }
+
+    /// Insert `value` so that it ends up at `index`, shifting later elements
+    /// up by one.
+    ///
+    /// Panics if `index > len` or if a new id can't be allocated.
+    pub fn insert(&mut self, index: usize, value: T) -> Op<T> {
+        match self.try_insert(index, value) {
+            Ok(op) => op,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `insert`, but returns an error if a new id can't be allocated,
+    /// for example because `NodeConfig::max_depth` has been reached.
+    ///
+    /// Panics if `index > len`.
+    pub fn try_insert(&mut self, index: usize, value: T) -> Result<Op<T>, LseqError> {
+        assert!(index <= self.len(), "index {} out of bounds (len {})", index, self.len());
+
+        let (lower_bound, upper_bound) = self.neighbours(index);
+        let id = self.node.try_new_id_between(lower_bound.as_ref(), upper_bound.as_ref())?;
+
+        let dot = self.next_dot();
+        self.contents.insert_block(IdBlock::single(id.clone()), vec![value.clone()], dot);
+        Ok(self.make_op(OpKind::Insert(id, value)))
+    }
+
+    /// Insert all of `values` so that the first ends up at `index`, shifting
+    /// later elements up. Uses `Node::new_block_between`, so is more efficient
+    /// than inserting the values one by one, both in terms of the size of the
+    /// ids and the size of the op (a single `OpKind::InsertBlock`, or no op
+    /// if `values` is empty).
+    ///
+    /// Panics if `index > len` or if new ids can't be allocated.
+    pub fn insert_many(&mut self, index: usize, values: Vec<T>) -> Option<Op<T>> {
+        match self.try_insert_many(index, values) {
+            Ok(op) => op,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `insert_many`, but returns an error if new ids can't be
+    /// allocated.
+    ///
+    /// Panics if `index > len`.
+    pub fn try_insert_many(&mut self, index: usize, values: Vec<T>) -> Result<Option<Op<T>>, LseqError> {
+        assert!(index <= self.len(), "index {} out of bounds (len {})", index, self.len());
+        if values.is_empty() {
+            return Ok(None);
+        }
+
+        let (lower_bound, upper_bound) = self.neighbours(index);
+        let ids = self.node.try_new_block_between(lower_bound.as_ref(), upper_bound.as_ref(), values.len())?;
+
+        let dot = self.next_dot();
+        self.contents.insert_block(ids.clone(), values.clone(), dot);
+        Ok(Some(self.make_op(OpKind::InsertBlock(ids, values))))
+    }
+
+    /// Remove the element at `index`, shifting later elements down by one.
+    ///
+    /// Panics if `index >= len`.
+    pub fn remove(&mut self, index: usize) -> Op<T> {
+        assert!(index < self.len(), "index {} out of bounds (len {})", index, self.len());
+
+        let id = self.id_at(index).unwrap();
+        let dot = self.next_dot();
+        self.contents.remove_id(&id, dot);
+        self.make_op(OpKind::Remove(id))
+    }
+
+    /// Remove `len` elements starting at `index`, shifting later elements
+    /// down. Returns a single `OpKind::Batch` op, or no op if `len` is 0.
+    ///
+    /// Panics if `index + len > len()`.
+    pub fn remove_many(&mut self, index: usize, len: usize) -> Option<Op<T>> {
+        assert!(
+            len <= self.len() && index <= self.len() - len,
+            "{} elements at index {} out of bounds (len {})",
+            len,
+            index,
+            self.len()
+        );
+        if len == 0 {
+            return None;
+        }
+
+        let dot = self.next_dot();
+        let ops = (0..len)
+            .map(|_| {
+                let id = self.id_at(index).unwrap();
+                self.contents.remove_id(&id, dot);
+                OpKind::Remove(id)
+            })
+            .collect();
+        Some(self.make_op(OpKind::Batch(ops)))
+    }
+
+    /// Give the `len` elements starting at `index` new ids, as short as the
+    /// elements either side of them allow and evenly spaced, so that later
+    /// inserts between them get short ids too. Returns an op which ends the
+    /// current epoch.
+    ///
+    /// If replicas rebalance the same epoch concurrently, every replica
+    /// applies the rebalance whose op has the least origin and seq, undoing
+    /// the other if it had already applied it (along with any later
+    /// rebalances based on it), so replicas converge whatever order they
+    /// receive the rebalances in. Ops based on an undone rebalance have
+    /// their ids translated back, see `Rebalance::untranslate`, so elements
+    /// inserted after it keep their ids and may move relative to the
+    /// elements around them. Coordinating rebalances (e.g., only a server
+    /// rebalances) avoids this.
+    ///
+    /// Ops from earlier epochs (e.g., created concurrently with the
+    /// rebalance) have their ids translated when they are applied, see
+    /// `Rebalance::translate`. Ops from later epochs are kept until the
+    /// rebalances before them have been applied.
+    ///
+    /// Panics if `index + len > len()` or if new ids can't be allocated.
+    pub fn rebalance(&mut self, index: usize, len: usize) -> Op<T> {
+        match self.try_rebalance(index, len) {
+            Ok(op) => op,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `rebalance`, but returns an error if new ids can't be allocated.
+    ///
+    /// Panics if `index + len > len()`.
+    pub fn try_rebalance(&mut self, index: usize, len: usize) -> Result<Op<T>, LseqError> {
+        assert!(
+            len <= self.len() && index <= self.len() - len,
+            "{} elements at index {} out of bounds (len {})",
+            len,
+            index,
+            self.len()
+        );
+
+        let (lower_bound, _) = self.neighbours(index);
+        let upper_bound = self.id_at(index + len);
+        // One more id than elements, see `Rebalance`.
+        let ids = self.node.try_new_block_between(lower_bound.as_ref(), upper_bound.as_ref(), len + 1)?;
+        let old = self.contents.blocks(index, len);
+
+        let rebalance = Rebalance::new(self.epoch(), self.base(), lower_bound, upper_bound, old, ids);
+        let dot = self.next_dot();
+        let op = self.make_op(OpKind::Rebalance(rebalance.clone()));
+        self.apply_rebalance(rebalance, dot);
+        Ok(op)
+    }
+
+    /// The number of rebalances this replica has applied.
+    pub fn epoch(&self) -> u64 {
+        self.rebalances.len() as u64
+    }
+
+    /// The origin and seq of the op of the rebalance which started the
+    /// current epoch, `None` in the first epoch.
+    pub fn base(&self) -> Option<(NodeId, u64)> {
+        self.rebalances.last().map(|(dot, _)| *dot)
+    }
+
+    /// The id which replaces `id`, an id from when this replica's `epoch`
+    /// and `base` were `epoch` and `base`, in the current epoch. Returns
+    /// `None` if it was dropped (see `Rebalance::translate`) or if the
+    /// rebalance `base` is not known. Useful for updating ids kept outside
+    /// the sequence, e.g., cursors.
+    pub fn translate(&self, id: &Id, epoch: u64, base: Option<(NodeId, u64)>) -> Option<Id> {
+        if self.epoch_after(base, &[]) != Some(epoch) {
+            return None;
+        }
+        let (undo, from) = self.path(epoch, base);
+        let id = undo.iter().fold(id.clone(), |id, rebalance| rebalance.untranslate(&id, self.node.config()));
+        self.rebalances[from..]
+            .iter()
+            .try_fold(id, |id, (_, rebalance)| rebalance.translate(&id, self.node.config()))
+    }
+
+    /// The ops this replica has created or applied (including those whose
+    /// changes were received in a `Delta` or by `merge`).
+    pub fn version(&self) -> &VersionVector {
+        &self.version
+    }
+
+    /// The ops which this replica has created or applied but which are not
+    /// included in `version`, in order of origin and then seq. To sync with
+    /// another replica, send it `missing_ops_since` its version.
+    ///
+    /// Ops whose changes were received in a `Delta` are not included, a
+    /// replica which has applied deltas should be synced with
+    /// `delta_since` instead.
+    pub fn missing_ops_since(&self, version: &VersionVector) -> Vec<Op<T>> {
+        self.log
+            .iter()
+            .flat_map(|(origin, ops)| ops.range(version.get(*origin) + 1..).map(|(_, op)| op.clone()))
+            .collect()
+    }
+
+    /// The changes made by the ops which this replica has created or applied
+    /// but which are not included in `version`. To sync with another
+    /// replica, send it `delta_since` its version.
+    pub fn delta_since(&self, version: &VersionVector) -> Delta<T> {
+        let unseen = |(origin, seq): &Dot| !version.contains(*origin, *seq);
+        Delta {
+            config: self.node.config().fingerprint(),
+            epoch: self.epoch(),
+            base: self.base(),
+            rebalances: self.all_rebalances().into_iter().filter(|(dot, _)| unseen(dot)).collect(),
+            dropped: self
+                .rebalances
+                .iter()
+                .zip(&self.dropped)
+                .map(|((dot, _), dropped)| (*dot, dropped.since(version)))
+                .filter(|(_, dropped)| !dropped.is_blank())
+                .collect(),
+            dots: self.seen.since(version),
+            contents: self.contents.since(version),
+        }
+    }
+
+    /// Apply a delta produced by any replica (including this one). Like
+    /// `apply_remote`, applying deltas is idempotent and commutative, and
+    /// deltas and ops may be mixed.
+    ///
+    /// Deltas from replicas with a different config, or with an id which
+    /// fails `Id::validate`, are rejected without changing the sequence, as
+    /// are deltas from a later epoch which don't include the rebalances
+    /// needed to reach it.
+    pub fn apply_delta(&mut self, delta: &Delta<T>) -> Result<(), LseqError> {
+        let expected = self.node.config().fingerprint();
+        if delta.config != expected {
+            return Err(LseqError::ConfigMismatch { expected, found: delta.config });
+        }
+        delta.contents.validate(self.node.config())?;
+        for (_, dropped) in &delta.dropped {
+            dropped.validate(self.node.config())?;
+        }
+
+        // Every rebalance must be known or in the delta, see `Rebalance::sort`.
+        let check_epoch = |epoch: u64, base: Option<Dot>| match self.epoch_after(base, &delta.rebalances) {
+            Some(expected) if expected != epoch => Err(LseqError::EpochMismatch { expected, found: epoch }),
+            Some(_) => Ok(()),
+            None => Err(LseqError::EpochMismatch { expected: self.epoch(), found: epoch }),
+        };
+        for (_, rebalance) in &delta.rebalances {
+            rebalance.validate(self.node.config())?;
+            check_epoch(rebalance.epoch(), rebalance.base())?;
+        }
+        check_epoch(delta.epoch, delta.base)?;
+        for (dot, _) in &delta.dropped {
+            if self.epoch_after(Some(*dot), &delta.rebalances).is_none() {
+                return Err(LseqError::InvalidState("dropped ids of an unknown rebalance"));
+            }
+        }
+
+        for (dot, rebalance) in &delta.rebalances {
+            self.add_rebalance(*dot, rebalance.clone());
+        }
+        for (dot, dropped) in &delta.dropped {
+            // From before the rebalance which dropped them.
+            let rebalance = self.find_rebalance(*dot).unwrap();
+            let (epoch, base) = (rebalance.epoch(), rebalance.base());
+            self.join_contents(dropped, epoch, base);
+        }
+        self.join_contents(&delta.contents, delta.epoch, delta.base);
+        self.seen.join(&delta.dots);
+        self.version = self.seen.version();
+        self.apply_pending()
+    }
+
+    /// Merge the state of `other` into this replica, so that it contains the
+    /// elements inserted in either replica and not removed in either, and
+    /// the ops logged by either.
+    ///
+    /// Merging is commutative, associative and idempotent (as far as the
+    /// contents, removed ids and logs of the replicas are concerned).
+    /// Returns an error without changing this replica if `other` has a
+    /// different config.
+    pub fn merge<S2: AllocationStrategy>(&mut self, other: &LSeq<T, S2>) -> Result<(), LseqError> {
+        let expected = self.node.config().fingerprint();
+        let found = other.node.config().fingerprint();
+        if found != expected {
+            return Err(LseqError::ConfigMismatch { expected, found });
+        }
+
+        for (dot, rebalance) in other.all_rebalances() {
+            self.add_rebalance(dot, rebalance);
+        }
+        for ((_, rebalance), dropped) in other.rebalances.iter().zip(&other.dropped) {
+            self.join_contents(dropped, rebalance.epoch(), rebalance.base());
+        }
+        self.join_contents(&other.contents, other.epoch(), other.base());
+        self.seen.join(&other.seen);
+        self.version = self.seen.version();
+        for (origin, ops) in &other.log {
+            let log = self.log.entry(*origin).or_default();
+            for (seq, op) in ops {
+                log.entry(*seq).or_insert_with(|| op.clone());
+            }
+        }
+        for op in &other.pending {
+            let dot = (op.origin, op.seq);
+            // Like `apply_remote`, ops past the limit are dropped.
+            if self.pending.len() < MAX_PENDING && !self.seen.contains(dot) && !self.pending.iter().any(|pending| (pending.origin, pending.seq) == dot) {
+                self.pending.push(op.clone());
+            }
+        }
+        self.apply_pending()
+    }
+
+    /// Apply an op produced by any replica (including this one).
+    ///
+    /// Applying an op is idempotent: inserting an id which is already present
+    /// or has been removed, and removing an id which is not present, are
+    /// no-ops. Ops commute, see `LSeq` for the convergence guarantee.
+    ///
+    /// Ops from replicas with a different config, or with an id which fails
+    /// `Id::validate`, are rejected without changing the sequence.
+    ///
+    /// Ops from earlier epochs, or based on a rebalance which lost to a
+    /// concurrent one, are translated. Ops based on a rebalance which hasn't
+    /// arrived yet are kept and applied once it does, see `LSeq::rebalance`.
+    /// Only a limited number of ops are kept, later ones are rejected and
+    /// must be sent again. Kept ops are not serialized with the replica, but
+    /// since they are not in its `version`, `missing_ops_since` will include
+    /// them again.
+    pub fn apply_remote(&mut self, op: Op<T>) -> Result<(), LseqError> {
+        let expected = self.node.config().fingerprint();
+        if op.config != expected {
+            return Err(LseqError::ConfigMismatch { expected, found: op.config });
+        }
+
+        let dot = (op.origin, op.seq);
+        if self.seen.contains(dot) {
+            return Ok(());
+        }
+
+        self.validate_op(&op.kind)?;
+        if let OpKind::Rebalance(rebalance) = &op.kind {
+            if rebalance.epoch() != op.epoch {
+                return Err(LseqError::EpochMismatch { expected: op.epoch, found: rebalance.epoch() });
+            }
+            if rebalance.base() != op.base {
+                return Err(LseqError::InvalidState("rebalance with a different base"));
+            }
+        }
+        match self.epoch_after(op.base, &[]) {
+            Some(expected) if expected != op.epoch => return Err(LseqError::EpochMismatch { expected, found: op.epoch }),
+            Some(_) => {}
+            None if self.pending.len() >= MAX_PENDING => return Err(LseqError::PendingLimit { limit: MAX_PENDING }),
+            None => {
+                self.pending.push(op);
+                return Ok(());
+            }
+        }
+
+        match op.kind.clone() {
+            OpKind::Rebalance(rebalance) => {
+                self.add_rebalance(dot, rebalance);
+                self.log_op(op);
+                self.apply_pending()
+            }
+            kind if op.epoch == self.epoch() && op.base == self.base() => {
+                apply_op(&mut self.contents, kind, dot);
+                self.log_op(op);
+                Ok(())
+            }
+            kind => {
+                let mut contents = Contents::new();
+                apply_op(&mut contents, kind, dot);
+                self.join_contents(&contents, op.epoch, op.base);
+                self.log_op(op);
+                Ok(())
+            }
+        }
+    }
+
+    fn validate_op(&self, kind: &OpKind<T>) -> Result<(), LseqError> {
+        match kind {
+            OpKind::Insert(id, _) | OpKind::Remove(id) => id.validate(self.node.config()),
+            OpKind::InsertBlock(ids, values) => {
+                ids.validate(self.node.config())?;
+                if ids.len() != values.len() {
+                    return Err(LseqError::InvalidBlock("wrong number of values"));
+                }
+                Ok(())
+            }
+            OpKind::Batch(kinds) => kinds.iter().try_for_each(|kind| match kind {
+                OpKind::Rebalance(_) => Err(LseqError::InvalidState("rebalance in a batch")),
+                kind => self.validate_op(kind),
+            }),
+            OpKind::Rebalance(rebalance) => rebalance.validate(self.node.config()),
+        }
+    }
+
+    // Add a rebalance whose base is known. The rebalance applied in each
+    // epoch is the one with the least op among those based on the rebalance
+    // applied in the previous epoch. So replicas which know the same
+    // rebalances apply the same ones, whatever order they arrived in.
+    fn add_rebalance(&mut self, dot: Dot, rebalance: Rebalance) {
+        if self.find_rebalance(dot).is_some() {
+            return;
+        }
+        if !self.wins(dot, &rebalance) {
+            self.losers.insert(dot, rebalance);
+            return;
+        }
+
+        // Undo the rebalance it beats, and the rebalances based on that.
+        while self.rebalances.len() > rebalance.epoch() as usize {
+            let (dot, rebalance) = self.rebalances.pop().unwrap();
+            let dropped = self.dropped.pop().unwrap();
+            self.contents.unrebalance(&rebalance, self.node.config());
+            self.contents.join(&dropped);
+            self.losers.insert(dot, rebalance);
+        }
+        self.apply_rebalance(rebalance, dot);
+    }
+
+    // Whether `rebalance`, whose base is known, should be applied instead
+    // of any rebalance applied in its epoch.
+    fn wins(&self, dot: Dot, rebalance: &Rebalance) -> bool {
+        let epoch = rebalance.epoch() as usize;
+        epoch <= self.rebalances.len()
+            && rebalance.base() == epoch.checked_sub(1).map(|e| self.rebalances[e].0)
+            && self.rebalances.get(epoch).is_none_or(|(applied, _)| dot < *applied)
+    }
+
+    fn apply_rebalance(&mut self, rebalance: Rebalance, dot: Dot) {
+        debug_assert_eq!(rebalance.epoch(), self.epoch());
+        let dropped = self.contents.rebalance(&rebalance, self.node.config());
+        self.rebalances.push((dot, rebalance));
+        self.dropped.push(dropped);
+    }
+
+    fn find_rebalance(&self, dot: Dot) -> Option<&Rebalance> {
+        self.losers
+            .get(&dot)
+            .or_else(|| self.rebalances.iter().find(|(applied, _)| *applied == dot).map(|(_, rebalance)| rebalance))
+    }
+
+    // Every rebalance this replica knows, see `Rebalance::sort`.
+    fn all_rebalances(&self) -> Vec<(Dot, Rebalance)> {
+        let mut rebalances: Vec<(Dot, Rebalance)> = self.rebalances.to_vec();
+        rebalances.extend(self.losers.iter().map(|(dot, rebalance)| (*dot, rebalance.clone())));
+        Rebalance::sort(&mut rebalances);
+        rebalances
+    }
+
+    // The epoch after the rebalance `base` (the first epoch if `None`), or
+    // `None` if it's neither known nor in `extra`.
+    fn epoch_after(&self, base: Option<Dot>, extra: &[(Dot, Rebalance)]) -> Option<u64> {
+        let dot = match base {
+            Some(dot) => dot,
+            None => return Some(0),
+        };
+        let rebalance = self
+            .find_rebalance(dot)
+            .or_else(|| extra.iter().find(|(other, _)| *other == dot).map(|(_, rebalance)| rebalance))?;
+        Some(rebalance.epoch() + 1)
+    }
+
+    // How to translate ids from `epoch`, started by the known rebalance
+    // `base`, to the current epoch: undo the returned losing rebalances,
+    // then apply the rebalances from the returned epoch on.
+    fn path(&self, epoch: u64, base: Option<Dot>) -> (Vec<&Rebalance>, usize) {
+        let mut undo = Vec::new();
+        let mut base = base;
+        let mut epoch = epoch as usize;
+        while let Some(dot) = base.filter(|dot| self.rebalances.get(epoch - 1).is_none_or(|(applied, _)| applied != dot)) {
+            let rebalance = &self.losers[&dot];
+            undo.push(rebalance);
+            base = rebalance.base();
+            epoch = rebalance.epoch() as usize;
+        }
+        (undo, epoch)
+    }
+
+    // Apply the ops which were waiting for a rebalance. Returns the first
+    // error, if any, after applying the rest.
+    fn apply_pending(&mut self) -> Result<(), LseqError> {
+        let mut result = Ok(());
+        for op in std::mem::take(&mut self.pending) {
+            result = result.and(self.apply_remote(op));
+        }
+        result
+    }
+
+    // Join `contents`, whose ids are from `epoch`, which was started by the
+    // known rebalance `base`.
+    fn join_contents(&mut self, contents: &Contents<T>, epoch: u64, base: Option<Dot>) {
+        if epoch == self.epoch() && base == self.base() {
+            self.contents.join(contents);
+            return;
+        }
+        let mut contents = contents.clone();
+        let (undo, from) = self.path(epoch, base);
+        for rebalance in undo {
+            contents.unrebalance(rebalance, self.node.config());
+        }
+        for ((_, rebalance), dropped) in self.rebalances[from..].iter().zip(&mut self.dropped[from..]) {
+            dropped.join(&contents.rebalance(rebalance, self.node.config()));
+        }
+        self.contents.join(&contents);
+    }
+
+    // The dot of the next op created by this replica.
+    fn next_dot(&self) -> Dot {
+        (self.node.id, self.version.get(self.node.id) + 1)
+    }
+
+    fn make_op(&mut self, kind: OpKind<T>) -> Op<T> {
+        let (origin, seq) = self.next_dot();
+        let op = Op {
+            config: self.node.config().fingerprint(),
+            origin,
+            seq,
+            epoch: self.epoch(),
+            base: self.base(),
+            kind,
+        };
+        self.log_op(op.clone());
+        op
+    }
+
+    fn log_op(&mut self, op: Op<T>) {
+        let origin = op.origin;
+        self.seen.insert((origin, op.seq));
+        self.log.entry(origin).or_default().insert(op.seq, op);
+        // Ops may arrive out of order, only count those with no gaps before
+        // them.
+        self.version.observe(origin, self.seen.prefix(origin));
+    }
+
+    // The ids either side of `index`, i.e., the bounds for a new element
+    // inserted at `index`.
+    fn neighbours(&self, index: usize) -> (Option<Id>, Option<Id>) {
+        let lower = index.checked_sub(1).and_then(|i| self.id_at(i));
+        (lower, self.id_at(index))
+    }
+}
+
+fn apply_op<T>(contents: &mut Contents<T>, kind: OpKind<T>, dot: Dot) {
+    match kind {
+        OpKind::Insert(id, value) => contents.insert_block(IdBlock::single(id), vec![value], dot),
+        OpKind::InsertBlock(ids, values) => contents.insert_block(ids, values, dot),
+        OpKind::Remove(id) => {
+            contents.remove_id(&id, dot);
+        }
+        OpKind::Batch(kinds) => {
+            for kind in kinds {
+                apply_op(contents, kind, dot);
+            }
+        }
+        // Applied by `LSeq::add_rebalance`, and not allowed in batches.
+        OpKind::Rebalance(_) => {}
+    }
+}
+
+impl<T: Serialize, S: Serialize> Serialize for LSeq<T, S> {
+    fn serialize<Se: Serializer>(&self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        #[derive(Serialize)]
+        #[serde(rename = "LSeq")]
+        struct Fields<'a, T, S> {
+            node: &'a Node<S>,
+            contents: &'a Contents<T>,
+            seen: &'a Dots,
+            log: Vec<&'a Op<T>>,
+            rebalances: &'a [(Dot, Rebalance)],
+            dropped: &'a [Contents<T>],
+            losers: Vec<(Dot, Rebalance)>,
+        }
+
+        let mut losers: Vec<(Dot, Rebalance)> = self.losers.iter().map(|(dot, rebalance)| (*dot, rebalance.clone())).collect();
+        Rebalance::sort(&mut losers);
+        Fields {
+            node: &self.node,
+            contents: &self.contents,
+            seen: &self.seen,
+            log: self.log.values().flat_map(|ops| ops.values()).collect(),
+            rebalances: &self.rebalances,
+            dropped: &self.dropped,
+            losers,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Deserializing checks that ids are valid for the replica's config and that
+/// elements are in order, so is safe to use on untrusted input.
+impl<'de, T, S> Deserialize<'de> for LSeq<T, S>
+where
+    T: Clone + Deserialize<'de>,
+    S: AllocationStrategy + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LSeq<T, S>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "LSeq")]
+        struct Fields<T, S> {
+            node: Node<S>,
+            contents: ContentsFields<T>,
+            seen: Dots,
+            log: Vec<Op<T>>,
+            rebalances: Vec<(Dot, Rebalance)>,
+            dropped: Vec<ContentsFields<T>>,
+            losers: Vec<(Dot, Rebalance)>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        let config = f.node.config();
+        let contents = Contents::from_fields(f.contents).map_err(de::Error::custom)?;
+        contents.validate(config).map_err(de::Error::custom)?;
+        let mut base = None;
+        for (epoch, (dot, rebalance)) in f.rebalances.iter().enumerate() {
+            if rebalance.epoch() != epoch as u64 || rebalance.base() != base {
+                return Err(de::Error::custom("rebalances out of order"));
+            }
+            rebalance.validate(config).map_err(de::Error::custom)?;
+            base = Some(*dot);
+        }
+        if f.dropped.len() != f.rebalances.len() {
+            return Err(de::Error::custom("wrong number of dropped contents"));
+        }
+        let mut dropped = Vec::new();
+        for fields in f.dropped {
+            let contents = Contents::from_fields(fields).map_err(de::Error::custom)?;
+            contents.validate(config).map_err(de::Error::custom)?;
+            dropped.push(contents);
+        }
+        let mut seq = LSeq {
+            node: f.node,
+            contents,
+            version: f.seen.version(),
+            seen: f.seen,
+            log: BTreeMap::new(),
+            rebalances: f.rebalances,
+            dropped,
+            losers: BTreeMap::new(),
+            pending: Vec::new(),
+        };
+        // Each loser must come after its base, and lose.
+        for (dot, rebalance) in f.losers {
+            rebalance.validate(seq.node.config()).map_err(de::Error::custom)?;
+            let known = seq.find_rebalance(dot).is_some();
+            if known || seq.epoch_after(rebalance.base(), &[]) != Some(rebalance.epoch()) || seq.wins(dot, &rebalance) {
+                return Err(de::Error::custom("inconsistent losing rebalance"));
+            }
+            seq.losers.insert(dot, rebalance);
+        }
+        for op in f.log {
+            if op.config != seq.node.config().fingerprint() {
+                return Err(de::Error::custom("op from a different config"));
+            }
+            if seq.epoch_after(op.base, &[]) != Some(op.epoch) {
+                return Err(de::Error::custom("op from an unknown epoch"));
+            }
+            seq.validate_op(&op.kind).map_err(de::Error::custom)?;
+            seq.log_op(op);
+        }
+        Ok(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    fn assert_ordered<T, S>(seq: &LSeq<T, S>) {
+        let ids: Vec<_> = seq.contents.elements().map(|(id, _)| id).collect();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+
+    fn to_string<S: AllocationStrategy>(seq: &LSeq<char, S>) -> String {
+        seq.iter().collect()
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut seq = LSeq::new(NodeId::new(0));
+        assert!(seq.is_empty());
+        for (i, c) in "Hello".chars().enumerate() {
+            seq.insert(i, c);
+        }
+        seq.insert(0, '>');
+        seq.insert(3, '_');
+        seq.insert(seq.len(), '!');
+        assert_eq!(&to_string(&seq), ">He_llo!");
+        assert_eq!(seq.len(), 8);
+        assert_eq!(seq.get(3), Some(&'_'));
+        assert_eq!(seq.get(8), None);
+        assert_ordered(&seq);
+    }
+
+    #[test]
+    fn test_insert_front() {
+        let mut seq = LSeq::new(NodeId::new(0));
+        for c in "olleH".chars() {
+            seq.insert(0, c);
+        }
+        assert_eq!(&to_string(&seq), "Hello");
+        assert_ordered(&seq);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut seq = LSeq::new(NodeId::new(0));
+        for (i, c) in "Hello, world!".chars().enumerate() {
+            seq.insert(i, c);
+        }
+        for _ in 0..7 {
+            seq.remove(5);
+        }
+        assert_eq!(&to_string(&seq), "Hello!");
+        assert_ordered(&seq);
+    }
+
+    #[test]
+    fn test_concurrent_same_ids() {
+        // a and b make the same random choices, so allocate ids with the same
+        // indices; the ids must still be distinct and leave room between them.
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut c = LSeq::new(NodeId::new(3));
+        a.set_seed(0);
+        b.set_seed(0);
+
+        let mut ops = Vec::new();
+        for i in 0..20 {
+            ops.push(a.insert(i, 'a'));
+            ops.push(b.insert(i, 'b'));
+        }
+        for op in ops.drain(..) {
+            a.apply_remote(op.clone()).unwrap();
+            b.apply_remote(op.clone()).unwrap();
+            c.apply_remote(op).unwrap();
+        }
+        assert_eq!(c.len(), 40);
+        for i in 0..40 {
+            ops.push(c.insert(i * 2 + 1, 'c'));
+        }
+        for op in ops {
+            a.apply_remote(op.clone()).unwrap();
+            b.apply_remote(op).unwrap();
+        }
+        assert_eq!(to_string(&a), to_string(&c));
+        assert_eq!(to_string(&b), to_string(&c));
+        assert_eq!(c.len(), 80);
+        assert_ordered(&c);
+    }
+
+    #[test]
+    fn test_insert_many() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+
+        let mut ops: Vec<_> = a.insert_many(0, "Hello!".chars().collect()).into_iter().collect();
+        ops.extend(a.insert_many(5, ", world".chars().collect()));
+        assert_eq!(a.insert_many(0, vec![]), None);
+        assert_eq!(&to_string(&a), "Hello, world!");
+        assert_ordered(&a);
+
+        for op in ops {
+            b.apply_remote(op).unwrap();
+        }
+        assert_eq!(&to_string(&b), "Hello, world!");
+    }
+
+    #[test]
+    fn test_runs() {
+        let mut seq = LSeq::new(NodeId::new(1));
+        let op = seq.insert_many(0, "Hello, world!".chars().collect()).unwrap();
+        assert!(matches!(op.kind, OpKind::InsertBlock(..)));
+        assert_eq!(seq.contents.run_count(), 1);
+
+        seq.insert(5, '_');
+        assert_eq!(seq.contents.run_count(), 3);
+        seq.remove(9);
+        assert_eq!(seq.contents.run_count(), 4);
+        seq.remove(0);
+        assert_eq!(seq.contents.run_count(), 4);
+        assert_eq!(&to_string(&seq), "ello_, wrld!");
+        assert_ordered(&seq);
+
+        for i in 0..seq.len() {
+            let id = seq.id_at(i).unwrap();
+            assert_eq!(seq.index_of(&id), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_apply_block_interleaved() {
+        // c inserts between elements of a's block, b receives c's ops before
+        // the block.
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut c = LSeq::new(NodeId::new(3));
+        let block = a.insert_many(0, "abcdef".chars().collect()).unwrap();
+        c.apply_remote(block.clone()).unwrap();
+        let ops = vec![c.insert(1, '_'), c.insert(5, '_')];
+
+        for op in &ops {
+            b.apply_remote(op.clone()).unwrap();
+        }
+        b.apply_remote(block.clone()).unwrap();
+        b.apply_remote(block).unwrap();
+        for op in ops {
+            a.apply_remote(op).unwrap();
+        }
+
+        assert_eq!(&to_string(&a), "a_bcd_ef");
+        assert_eq!(&to_string(&b), "a_bcd_ef");
+        assert_eq!(b.contents.run_count(), 5);
+        assert_ordered(&a);
+        assert_ordered(&b);
+    }
+
+    #[test]
+    fn test_index_of() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut ops = Vec::new();
+        for i in 0..2000 {
+            let index = (i * 7919) % (a.len() + 1);
+            ops.push(a.insert(index, i));
+            if i % 3 == 0 {
+                ops.push(a.remove(index / 2));
+            }
+        }
+        for op in ops {
+            b.apply_remote(op).unwrap();
+        }
+        assert_ordered(&a);
+
+        assert_eq!(a.len(), b.len());
+        for i in 0..a.len() {
+            let id = a.id_at(i).unwrap();
+            assert_eq!(a.index_of(&id), Some(i));
+            assert_eq!(b.index_of(&id), Some(i));
+            assert_eq!(b.get_by_id(&id), a.get(i));
+        }
+
+        let op = a.remove(10);
+        if let OpKind::Remove(ref id) = op.kind {
+            assert_eq!(a.index_of(id), None);
+            assert_eq!(a.get_by_id(id), None);
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        use crate::{BoundaryMinus, BoundaryPlus, HashMix};
+
+        let mut a = LSeq::with_strategy(NodeId::new(1), NodeConfig::new(), BoundaryPlus);
+        let mut b = LSeq::with_strategy(NodeId::new(2), NodeConfig::new(), BoundaryMinus);
+        let mut c = LSeq::with_strategy(NodeId::new(3), NodeConfig::new(), HashMix);
+
+        let mut ops = Vec::new();
+        for i in 0..50 {
+            ops.push(a.insert(a.len(), 'a'));
+            ops.push(b.insert(0, 'b'));
+            ops.push(c.insert(i % (c.len() + 1), 'c'));
+        }
+        for op in ops {
+            a.apply_remote(op.clone()).unwrap();
+            b.apply_remote(op.clone()).unwrap();
+            c.apply_remote(op).unwrap();
+        }
+        assert_eq!(a.len(), 150);
+        assert_eq!(to_string(&a), to_string(&b));
+        assert_eq!(to_string(&a), to_string(&c));
+        assert_ordered(&a);
+    }
+
+    #[test]
+    fn test_replay() {
+        fn session(seed: u64) -> Vec<Op<usize>> {
+            let mut a = LSeq::new(NodeId::new(1));
+            let mut b = LSeq::new(NodeId::new(2));
+            a.set_seed(seed);
+            b.set_seed(seed + 1);
+
+            let mut ops = Vec::new();
+            for i in 0..100 {
+                let op = a.insert((i * 7) % (a.len() + 1), i);
+                b.apply_remote(op.clone()).unwrap();
+                ops.push(op);
+                let op = b.insert((i * 3) % (b.len() + 1), i);
+                a.apply_remote(op.clone()).unwrap();
+                ops.push(op);
+            }
+            ops
+        }
+
+        assert_eq!(session(7), session(7));
+        assert_ne!(session(7), session(8));
+    }
+
+    #[test]
+    fn test_try_insert_depth_limit() {
+        let config = NodeConfig::builder().max_depth(2).build();
+        let mut seq = LSeq::with_config(NodeId::new(0), config);
+        let mut result = Ok(());
+        for _ in 0..100 {
+            if let Err(e) = seq.try_insert(0, 'a') {
+                result = Err(e);
+                break;
+            }
+        }
+        assert_eq!(result, Err(LseqError::DepthLimitExceeded { max_depth: 2 }));
+        assert_ordered(&seq);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_insert_out_of_bounds() {
+        let mut seq = LSeq::new(NodeId::new(0));
+        seq.insert(1, 'a');
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+
+        let mut ops = Vec::new();
+        for (i, c) in "Hello".chars().enumerate() {
+            ops.push(a.insert(i, c));
+        }
+        for op in ops.drain(..) {
+            b.apply_remote(op).unwrap();
+        }
+        assert_eq!(&to_string(&b), "Hello");
+
+        ops.push(b.insert(5, '!'));
+        ops.push(a.insert(0, '>'));
+        ops.push(b.remove(0));
+        for op in ops {
+            a.apply_remote(op.clone()).unwrap();
+            b.apply_remote(op).unwrap();
+        }
+        assert_eq!(&to_string(&a), ">ello!");
+        assert_eq!(&to_string(&b), ">ello!");
+        assert_ordered(&a);
+        assert_ordered(&b);
+    }
+
+    #[test]
+    fn test_apply_idempotent() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+
+        let op = a.insert(0, 'a');
+        b.apply_remote(op.clone()).unwrap();
+        b.apply_remote(op.clone()).unwrap();
+        a.apply_remote(op.clone()).unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(b.len(), 1);
+
+        let insert = op;
+        let op = a.remove(0);
+        b.apply_remote(op.clone()).unwrap();
+        b.apply_remote(op).unwrap();
+        assert!(b.is_empty());
+
+        // Removed elements stay removed.
+        a.apply_remote(insert.clone()).unwrap();
+        b.apply_remote(insert).unwrap();
+        assert!(a.is_empty());
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn test_remove_before_insert() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut c = LSeq::new(NodeId::new(3));
+
+        let insert = a.insert_many(0, "abcdef".chars().collect()).unwrap();
+        b.apply_remote(insert.clone()).unwrap();
+        let remove = b.remove_many(1, 2).unwrap();
+        let single = b.insert(0, '_');
+        let remove_single = b.remove(0);
+
+        c.apply_remote(remove).unwrap();
+        c.apply_remote(remove_single).unwrap();
+        assert!(c.is_empty());
+        c.apply_remote(insert).unwrap();
+        c.apply_remote(single).unwrap();
+        assert_eq!(&to_string(&b), "adef");
+        assert_eq!(&to_string(&c), "adef");
+        assert_ordered(&c);
+    }
+
+    #[test]
+    fn test_remove_many() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let op = a.insert_many(0, "Hello, world!".chars().collect()).unwrap();
+        b.apply_remote(op).unwrap();
+
+        let op = a.remove_many(5, 7).unwrap();
+        assert_eq!(&to_string(&a), "Hello!");
+        b.apply_remote(op.clone()).unwrap();
+        b.apply_remote(op).unwrap();
+        assert_eq!(&to_string(&b), "Hello!");
+        assert_ordered(&b);
+
+        assert_eq!(a.remove_many(2, 0), None);
+        assert_eq!(a.len(), 6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_remove_many_out_of_bounds() {
+        let mut seq = LSeq::new(NodeId::new(0));
+        seq.insert_many(0, vec!['a', 'b']);
+        seq.remove_many(1, 2);
+    }
+
+    #[test]
+    fn test_convergence() {
+        // Three replicas edit concurrently, occasionally syncing with each
+        // other, then each op is delivered to new replicas in a random order
+        // (so removes may arrive before inserts), some of them twice.
+        let mut rng = XorShiftRng::seed_from_u64(11);
+        let mut replicas: Vec<LSeq<u32>> = (0..3).map(|i| LSeq::new(NodeId::new(i))).collect();
+        let mut inserts = Vec::new();
+        let mut removes = Vec::new();
+        for i in 0..300 {
+            let r = &mut replicas[i as usize % 3];
+            let index = rng.gen_range(0, r.len() + 1);
+            if r.len() > 2 && rng.gen_range(0, 4) == 0 {
+                removes.extend(r.remove_many(index.min(r.len() - 2), 2));
+            } else if rng.gen() {
+                inserts.push(r.insert(index, i));
+            } else {
+                inserts.extend(r.insert_many(index, vec![i; 3]));
+            }
+
+            if i % 50 == 49 {
+                for r in replicas.iter_mut() {
+                    for op in inserts.iter().chain(&removes) {
+                        r.apply_remote(op.clone()).unwrap();
+                    }
+                }
+            }
+        }
+
+        let mut expected = None;
+        for _ in 0..5 {
+            let mut ops: Vec<_> = inserts.iter().chain(&removes).cloned().collect();
+            ops.extend(ops.clone().into_iter().filter(|_| rng.gen()));
+            rng.shuffle(&mut ops);
+
+            let mut seq = LSeq::new(NodeId::new(9));
+            for op in ops {
+                seq.apply_remote(op).unwrap();
+            }
+            assert_ordered(&seq);
+            let contents: Vec<_> = seq.iter().cloned().collect();
+            assert_eq!(expected.get_or_insert(contents.clone()), &contents);
+        }
+
+        for r in replicas.iter_mut() {
+            for op in inserts.iter().chain(&removes) {
+                r.apply_remote(op.clone()).unwrap();
+            }
+            assert_eq!(r.iter().cloned().collect::<Vec<_>>(), *expected.as_ref().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_apply_batch_invalid() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let config = NodeConfig::new().fingerprint();
+        let ok = "3@2:1".parse().unwrap();
+        let wide = "99@2:1".parse().unwrap();
+        let op = Op { config, origin: NodeId::new(2), seq: 1, epoch: 0, base: None, kind: OpKind::Batch(vec![OpKind::Insert(ok, 'a'), OpKind::Insert(wide, 'b')]) };
+        let err = a.apply_remote(op).unwrap_err();
+        assert_eq!(err, LseqError::IndexOutOfWidth { level: 0, index: 99, width: 16 });
+        assert!(a.is_empty());
+    }
+
+    #[test]
+    fn test_version() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let first = a.insert(0, 'a');
+        let second = a.insert(1, 'b');
+        let third = a.remove(0);
+        assert_eq!((first.seq, second.seq, third.seq), (1, 2, 3));
+        assert_eq!(a.version().get(NodeId::new(1)), 3);
+
+        // Out of order ops are applied, but only counted once the gap is
+        // filled.
+        b.apply_remote(third.clone()).unwrap();
+        b.apply_remote(first).unwrap();
+        assert_eq!(b.version().get(NodeId::new(1)), 1);
+        assert_eq!(b.missing_ops_since(&VersionVector::new()).len(), 2);
+        b.apply_remote(second).unwrap();
+        assert_eq!(b.version(), a.version());
+        assert_eq!(&to_string(&b), "b");
+
+        let op = b.insert(0, 'c');
+        assert_eq!((op.origin, op.seq), (NodeId::new(2), 1));
+        assert!(b.version() > a.version());
+        assert_eq!(b.missing_ops_since(a.version()), vec![op]);
+        assert_eq!(a.missing_ops_since(b.version()), vec![]);
+        assert_eq!(a.missing_ops_since(&VersionVector::new()).last(), Some(&third));
+    }
+
+    #[test]
+    fn test_sync() {
+        // Replicas edit while partitioned, then reconcile by exchanging
+        // versions.
+        let mut replicas: Vec<LSeq<u32>> = (0..3).map(|i| LSeq::new(NodeId::new(i))).collect();
+        for i in 0..100 {
+            let r = &mut replicas[i as usize % 3];
+            let index = (i as usize * 7919) % (r.len() + 1);
+            if i % 4 == 3 {
+                r.remove(index.min(r.len() - 1));
+            } else {
+                r.insert_many(index, vec![i, i]);
+            }
+            if i % 10 == 9 {
+                let ops = replicas[0].missing_ops_since(replicas[1].version());
+                for op in ops {
+                    replicas[1].apply_remote(op).unwrap();
+                }
+            }
+        }
+        assert_eq!(replicas[2].missing_ops_since(replicas[1].version()).len(), 33);
+
+        for (i, j) in &[(0, 1), (1, 2), (2, 0), (0, 1)] {
+            let ops = replicas[*i].missing_ops_since(replicas[*j].version());
+            for op in ops {
+                replicas[*j].apply_remote(op).unwrap();
+            }
+        }
+        for r in &replicas[1..] {
+            assert_eq!(r.version(), replicas[0].version());
+            assert_eq!(r.iter().collect::<Vec<_>>(), replicas[0].iter().collect::<Vec<_>>());
+            assert!(r.missing_ops_since(replicas[0].version()).is_empty());
+        }
+    }
+
+    // Checks that the contents, seen ops and logs of two replicas are equal.
+    fn assert_same<T, S1, S2>(a: &LSeq<T, S1>, b: &LSeq<T, S2>)
+    where
+        T: Clone + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(a.contents, b.contents);
+        assert_eq!(a.seen, b.seen);
+        assert_eq!(a.version, b.version);
+        assert_eq!(a.log, b.log);
+        assert_eq!(a.rebalances, b.rebalances);
+        assert_eq!(a.dropped, b.dropped);
+        assert_eq!(a.losers, b.losers);
+    }
+
+    fn random_edit(seq: &mut LSeq<u32>, rng: &mut XorShiftRng, value: u32) -> Op<u32> {
+        let index = rng.gen_range(0, seq.len() + 1);
+        match rng.gen_range(0, 4) {
+            0 if !seq.is_empty() => seq.remove(index.min(seq.len() - 1)),
+            1 => seq.insert_many(index, vec![value; 3]).unwrap(),
+            _ => seq.insert(index, value),
+        }
+    }
+
+    // A replica which has seen some of a shared history, then made edits of
+    // its own. The same seed always gives the same replica.
+    fn random_replica(seed: u64) -> LSeq<u32> {
+        let mut base_rng = XorShiftRng::seed_from_u64(0);
+        let mut base = LSeq::new(NodeId::new(0));
+        base.set_seed(0);
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let mut seq = LSeq::new(NodeId::from(seed));
+        seq.set_seed(seed);
+
+        for i in 0..50 {
+            let op = random_edit(&mut base, &mut base_rng, i);
+            if rng.gen() {
+                seq.apply_remote(op).unwrap();
+            }
+        }
+        for i in 0..30 {
+            random_edit(&mut seq, &mut rng, 100 + i);
+        }
+        seq
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = random_replica(1);
+        let b = random_replica(2);
+        a.merge(&b).unwrap();
+        assert_ordered(&a);
+
+        // Merging gives the same result as exchanging ops.
+        let mut c = random_replica(2);
+        for op in random_replica(1).missing_ops_since(c.version()) {
+            c.apply_remote(op).unwrap();
+        }
+        assert_same(&a, &c);
+        assert_eq!(a.missing_ops_since(c.version()), c.missing_ops_since(c.version()));
+    }
+
+    // Like `random_replica`, but the shared history and the replica's own
+    // edits include rebalances of random ranges, so replicas may have
+    // rebalanced concurrently. Seeds must be positive.
+    fn random_state(seed: u64) -> LSeq<u32> {
+        let rebalance = |seq: &mut LSeq<u32>, rng: &mut XorShiftRng| {
+            let len = rng.gen_range(0, seq.len() + 1);
+            let index = rng.gen_range(0, seq.len() - len + 1);
+            seq.rebalance(index, len)
+        };
+        let mut base_rng = XorShiftRng::seed_from_u64(0);
+        let mut base = LSeq::new(NodeId::new(0));
+        base.set_seed(0);
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let mut seq = LSeq::new(NodeId::from(seed));
+        seq.set_seed(seed);
+
+        for i in 0..40 {
+            let op = if i % 15 == 14 { rebalance(&mut base, &mut base_rng) } else { random_edit(&mut base, &mut base_rng, i) };
+            if rng.gen() {
+                seq.apply_remote(op).unwrap();
+            }
+        }
+        for i in 0..20 {
+            if rng.gen_range(0, 8) == 0 {
+                rebalance(&mut seq, &mut rng);
+            } else {
+                random_edit(&mut seq, &mut rng, 100 + i);
+            }
+        }
+        seq
+    }
+
+    #[test]
+    fn test_merge_properties() {
+        // Whether any of the states had rebalanced, lost a rebalance and
+        // removed elements.
+        let (mut rebalanced, mut lost, mut removed) = (false, false, false);
+        for seed in 0..200 {
+            let (x, y, z) = (3 * seed + 1, 3 * seed + 2, 3 * seed + 3);
+
+            let mut xx = random_state(x);
+            xx.merge(&random_state(x)).unwrap();
+            assert_same(&xx, &random_state(x));
+
+            let mut xy = random_state(x);
+            xy.merge(&random_state(y)).unwrap();
+            let mut yx = random_state(y);
+            yx.merge(&random_state(x)).unwrap();
+            assert_same(&xy, &yx);
+            assert_ordered(&xy);
+
+            let mut xy_z = xy;
+            xy_z.merge(&random_state(z)).unwrap();
+            let mut yz = random_state(y);
+            yz.merge(&random_state(z)).unwrap();
+            let mut x_yz = random_state(x);
+            x_yz.merge(&yz).unwrap();
+            assert_same(&xy_z, &x_yz);
+            assert_ordered(&x_yz);
+
+            xy_z.merge(&yz).unwrap();
+            assert_same(&xy_z, &x_yz);
+
+            rebalanced |= x_yz.epoch() > 1;
+            lost |= !x_yz.losers.is_empty();
+            removed |= x_yz.log.values().flat_map(BTreeMap::values).any(|op| matches!(op.kind, OpKind::Remove(_)));
+        }
+        assert!(rebalanced && lost && removed);
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut a = random_replica(1);
+        let mut b = random_replica(2);
+        let (va, vb) = (a.version().clone(), b.version().clone());
+        let to_b = a.delta_since(&vb);
+        let to_a = b.delta_since(&va);
+        assert!(!to_b.is_empty());
+        a.apply_delta(&to_a).unwrap();
+        b.apply_delta(&to_b).unwrap();
+        b.apply_delta(&to_b).unwrap();
+        assert_eq!(a.contents, b.contents);
+        assert_eq!(a.version(), b.version());
+        assert_ordered(&a);
+
+        // Ops after a gap in the version are sent again, which changes nothing.
+        let before = b.contents.clone();
+        b.apply_delta(&a.delta_since(b.version())).unwrap();
+        assert_eq!(b.contents, before);
+
+        // Ops already received in a delta are skipped.
+        for op in random_replica(1).missing_ops_since(&vb) {
+            b.apply_remote(op).unwrap();
+        }
+        assert_eq!(a.contents, b.contents);
+
+        let mut merged = random_replica(1);
+        merged.merge(&random_replica(2)).unwrap();
+        assert_eq!(merged.contents, a.contents);
+        assert_eq!(merged.version(), a.version());
+    }
+
+    #[test]
+    fn test_delta_size() {
+        // Typing and deleting, a delta only includes what is left.
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut ops = Vec::new();
+        for i in 0..100 {
+            ops.push(a.insert(a.len(), i));
+            if i % 2 == 1 {
+                ops.push(a.remove(a.len() - 1));
+            }
+        }
+        let delta = a.delta_since(&VersionVector::new());
+        let delta_size = bincode::serialize(&delta).unwrap().len();
+        let ops_size = bincode::serialize(&ops).unwrap().len();
+        assert!(delta_size < ops_size, "{} vs {}", delta_size, ops_size);
+        assert!(a.delta_since(a.version()).is_empty());
+
+        let mut b = LSeq::new(NodeId::new(2));
+        b.apply_delta(&delta).unwrap();
+        assert_eq!(b.iter().collect::<Vec<_>>(), a.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_delta_join() {
+        let mut a = random_replica(1);
+        let mut c = random_replica(3);
+        let v0 = c.version().clone();
+        let first = a.delta_since(&v0);
+        let v1 = a.version().clone();
+        let mut rng = XorShiftRng::seed_from_u64(4);
+        for i in 0..20 {
+            random_edit(&mut a, &mut rng, 1000 + i);
+        }
+        let second = a.delta_since(&v1);
+        assert!(!second.is_empty());
+
+        let mut joined = first.clone();
+        joined.join(&second).unwrap();
+        let mut other_way = second.clone();
+        other_way.join(&first).unwrap();
+        assert_eq!(joined, other_way);
+        let mut again = joined.clone();
+        again.join(&first).unwrap();
+        assert_eq!(again, joined);
+
+        let mut d = random_replica(3);
+        c.apply_delta(&joined).unwrap();
+        d.apply_delta(&second).unwrap();
+        d.apply_delta(&first).unwrap();
+        assert_eq!(c.contents, d.contents);
+        assert_eq!(c.version(), d.version());
+
+        let bytes = bincode::serialize(&joined).unwrap();
+        let decoded: Delta<u32> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, joined);
+
+        let config = NodeConfig::builder().initial_width(64).build();
+        let mut wide: LSeq<u32> = LSeq::with_config(NodeId::new(9), config);
+        wide.insert(0, 1);
+        let delta = wide.delta_since(&VersionVector::new());
+        assert!(joined.join(&delta).is_err());
+        assert!(c.apply_delta(&delta).is_err());
+    }
+
+    #[test]
+    fn test_delta_unreliable() {
+        // Deltas are dropped, duplicated and reordered, and replicas request
+        // new ones based on stale versions.
+        let mut rng = XorShiftRng::seed_from_u64(5);
+        let mut replicas: Vec<LSeq<u32>> = (0..3).map(|i| LSeq::new(NodeId::new(i))).collect();
+        let mut in_flight: Vec<(usize, Delta<u32>)> = Vec::new();
+        for i in 0..300 {
+            let r = rng.gen_range(0, 3);
+            random_edit(&mut replicas[r], &mut rng, i);
+
+            let to = rng.gen_range(0, 3);
+            let from = rng.gen_range(0, 3);
+            let delta = replicas[from].delta_since(replicas[to].version());
+            if rng.gen_range(0, 3) > 0 {
+                in_flight.push((to, delta));
+            }
+            if !in_flight.is_empty() && rng.gen() {
+                let (to, delta) = in_flight.swap_remove(rng.gen_range(0, in_flight.len()));
+                replicas[to].apply_delta(&delta).unwrap();
+                if rng.gen() {
+                    in_flight.push((to, delta));
+                }
+            }
+        }
+
+        for _ in 0..2 {
+            for from in 0..3 {
+                for to in 0..3 {
+                    let delta = replicas[from].delta_since(replicas[to].version());
+                    replicas[to].apply_delta(&delta).unwrap();
+                }
+            }
+        }
+        for r in &replicas[1..] {
+            assert_eq!(r.contents, replicas[0].contents);
+            assert_eq!(r.version(), replicas[0].version());
+        }
+        assert_ordered(&replicas[0]);
+    }
+
+    #[test]
+    fn test_merge_config_mismatch() {
+        let config = NodeConfig::builder().initial_width(64).build();
+        let mut a = LSeq::with_config(NodeId::new(1), config);
+        let b = random_replica(1);
+        a.insert(0, 7);
+        assert!(a.merge(&b).is_err());
+        assert_eq!(a.len(), 1);
+    }
+
+    #[test]
+    fn test_serde() {
+        let a = random_replica(3);
+        let bytes = bincode::serialize(&a).unwrap();
+        let mut b: LSeq<u32> = bincode::deserialize(&bytes).unwrap();
+        assert_same(&a, &b);
+        assert_eq!(b.node_id(), NodeId::new(3));
+
+        // The restored replica carries on where it left off.
+        let op = b.insert(2, 1000);
+        assert_eq!(op.seq, a.version().get(NodeId::new(3)) + 1);
+        assert_eq!(a.index_of(&b.id_at(2).unwrap()), None);
+        assert_ordered(&b);
+
+        // Logged ops must be from the replica's config, like ops applied to it.
+        let mut c = random_replica(3);
+        c.log.values_mut().next().unwrap().values_mut().next().unwrap().config += 1;
+        let err = bincode::deserialize::<LSeq<u32>>(&bincode::serialize(&c).unwrap()).err().unwrap();
+        assert_eq!(err.to_string(), "op from a different config");
+    }
+
+    #[test]
+    fn test_apply_config_mismatch() {
+        let config = NodeConfig::builder().initial_width(64).build();
+        let mut a = LSeq::with_config(NodeId::new(1), config.clone());
+        let mut b = LSeq::new(NodeId::new(2));
+
+        let op = a.insert(0, 'a');
+        let err = b.apply_remote(op.clone()).unwrap_err();
+        assert_eq!(err, LseqError::ConfigMismatch {
+            expected: NodeConfig::new().fingerprint(),
+            found: config.fingerprint(),
+        });
+        assert!(b.is_empty());
+
+        let mut c = LSeq::with_config(NodeId::new(3), config);
+        c.apply_remote(op).unwrap();
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn test_apply_invalid_id() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let config = NodeConfig::new().fingerprint();
+        let wide = "99@2:1".parse().unwrap();
+        let err = a.apply_remote(Op { config, origin: NodeId::new(2), seq: 1, epoch: 0, base: None, kind: OpKind::Insert(wide, 'a') }).unwrap_err();
+        assert_eq!(err, LseqError::IndexOutOfWidth { level: 0, index: 99, width: 16 });
+        let zero = "3@2:1.0".parse().unwrap();
+        let err = a.apply_remote(Op { config, origin: NodeId::new(2), seq: 1, epoch: 0, base: None, kind: OpKind::Insert(zero, 'a') }).unwrap_err();
+        assert_eq!(err, LseqError::ZeroLastIndex);
+
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut op = b.insert_many(0, vec!['a', 'b']).unwrap();
+        if let OpKind::InsertBlock(_, ref mut values) = op.kind {
+            values.pop();
+        }
+        let err = a.apply_remote(op).unwrap_err();
+        assert_eq!(err, LseqError::InvalidBlock("wrong number of values"));
+        assert!(a.is_empty());
+    }
+
+    fn max_depth<T, S>(seq: &LSeq<T, S>) -> usize {
+        seq.contents.elements().map(|(id, _)| id.depth()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let mut a = LSeq::new(NodeId::new(1));
+        a.set_seed(1);
+        let mut b = LSeq::new(NodeId::new(2));
+        // Typing in the middle makes ids deep.
+        let mut ops: Vec<_> = a.insert_many(0, "ab".chars().collect()).into_iter().collect();
+        for c in "Hello, world".chars() {
+            let index = a.len() - 1;
+            ops.push(a.insert(index, c));
+        }
+        ops.push(a.remove(3));
+        let deep = max_depth(&a);
+        assert!(deep > 2, "{}", deep);
+
+        let text = to_string(&a);
+        let old_id = a.id_at(5).unwrap();
+        ops.push(a.rebalance(0, a.len()));
+        assert_eq!(to_string(&a), text);
+        assert_eq!(a.epoch(), 1);
+        assert_eq!(max_depth(&a), 1);
+        assert_eq!(a.translate(&old_id, 0, None), a.id_at(5));
+        assert_ordered(&a);
+
+        // Ops after the rebalance have the next epoch.
+        ops.push(a.insert(3, '_'));
+        assert_eq!(ops.last().unwrap().epoch, 1);
+
+        for op in ops.iter().rev() {
+            b.apply_remote(op.clone()).unwrap();
+        }
+        assert_eq!(b.contents, a.contents);
+        assert_eq!(b.epoch(), 1);
+        assert_eq!(b.version(), a.version());
+    }
+
+    #[test]
+    fn test_rebalance_concurrent() {
+        let mut a = LSeq::new(NodeId::new(1));
+        let mut b = LSeq::new(NodeId::new(2));
+        let mut c = LSeq::new(NodeId::new(3));
+        let op = a.insert_many(0, "abcdefgh".chars().collect()).unwrap();
+        b.apply_remote(op.clone()).unwrap();
+        c.apply_remote(op).unwrap();
+
+        // `a` rebalances "cdef" while `b` edits inside and around it.
+        let rebalance = a.rebalance(2, 4);
+        let b_ops = vec![b.insert(4, '1'), b.remove(3), b.insert(2, '2'), b.insert(7, '3'), b.insert(1, '4')];
+        let after = a.insert(0, '>');
+        assert_eq!(&to_string(&b), "a4b2c1ef3gh");
+
+        // `after` arrives before the rebalance which it depends on.
+        c.apply_remote(after.clone()).unwrap();
+        assert_eq!(&to_string(&c), "abcdefgh");
+        // Only so many ops are kept.
+        let mut d = LSeq::new(NodeId::new(4));
+        for seq in 0..=MAX_PENDING as u64 {
+            let op = Op { seq: after.seq + seq, ..after.clone() };
+            let expected = if seq < MAX_PENDING as u64 { Ok(()) } else { Err(LseqError::PendingLimit { limit: MAX_PENDING }) };
+            assert_eq!(d.apply_remote(op), expected);
+        }
+        assert_eq!(d.pending.len(), MAX_PENDING);
+        for op in b_ops.iter().take(2) {
+            c.apply_remote(op.clone()).unwrap();
+        }
+        c.apply_remote(rebalance.clone()).unwrap();
+        for op in b_ops.iter().skip(2) {
+            c.apply_remote(op.clone()).unwrap();
+        }
+
+        b.apply_remote(rebalance).unwrap();
+        b.apply_remote(after).unwrap();
+        for op in b_ops {
+            a.apply_remote(op).unwrap();
+        }
+
+        assert_eq!(&to_string(&a), ">a4b2c1ef3gh");
+        for seq in &[&b, &c] {
+            assert_eq!(seq.contents, a.contents);
+            assert_eq!(seq.version(), a.version());
+        }
+        assert_ordered(&a);
+    }
+
+    #[test]
+    fn test_rebalance_random() {
+        // Replica 0 rebalances now and then, ops are delivered late, out of
+        // order and more than once.
+        let mut rng = XorShiftRng::seed_from_u64(6);
+        let mut replicas: Vec<LSeq<u32>> = (0..3).map(|i| LSeq::new(NodeId::new(i))).collect();
+        let mut in_flight: Vec<(usize, Op<u32>)> = Vec::new();
+        for i in 0..400 {
+            let r = if i % 40 == 39 { 0 } else { rng.gen_range(0, 3) };
+            let op = if i % 40 == 39 {
+                let index = rng.gen_range(0, replicas[0].len() + 1);
+                let len = rng.gen_range(0, replicas[0].len() - index + 1);
+                replicas[0].rebalance(index, len)
+            } else {
+                random_edit(&mut replicas[r], &mut rng, i)
+            };
+            for to in (0..3).filter(|&to| to != r) {
+                in_flight.push((to, op.clone()));
+            }
+            while !in_flight.is_empty() && rng.gen_range(0, 3) > 0 {
+                let (to, op) = in_flight.swap_remove(rng.gen_range(0, in_flight.len()));
+                replicas[to].apply_remote(op.clone()).unwrap();
+                if rng.gen_range(0, 4) == 0 {
+                    in_flight.push((to, op));
+                }
+            }
+        }
+        for (to, op) in in_flight {
+            replicas[to].apply_remote(op).unwrap();
+        }
+
+        assert_eq!(replicas[0].epoch(), 10);
+        for r in &replicas[1..] {
+            assert_eq!(r.contents, replicas[0].contents);
+            assert_eq!(r.version(), replicas[0].version());
+            assert_eq!(r.epoch(), replicas[0].epoch());
+        }
+        assert_ordered(&replicas[0]);
+    }
+
+    #[test]
+    fn test_rebalance_state() {
+        let mut a = random_replica(1);
+        let mut b = random_replica(2);
+        let v0 = b.version().clone();
+        a.rebalance(0, a.len());
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        for i in 0..20 {
+            random_edit(&mut a, &mut rng, 1000 + i);
+            random_edit(&mut b, &mut rng, 2000 + i);
+        }
+
+        // Deltas and merges bring older replicas up to date, and translate
+        // changes from older epochs.
+        let mut c = random_replica(2);
+        c.apply_delta(&a.delta_since(&v0)).unwrap();
+        c.apply_delta(&b.delta_since(&v0)).unwrap();
+        let mut d = random_replica(1);
+        d.merge(&b).unwrap();
+        d.merge(&a).unwrap();
+        a.apply_delta(&b.delta_since(a.version())).unwrap();
+        b.merge(&a).unwrap();
+        for seq in &[&b, &c, &d] {
+            assert_eq!(seq.contents, a.contents);
+            assert_eq!(seq.version(), a.version());
+            assert_eq!(seq.epoch(), 1);
+        }
+        assert_ordered(&a);
+
+        let bytes = bincode::serialize(&a).unwrap();
+        let restored: LSeq<u32> = bincode::deserialize(&bytes).unwrap();
+        assert_same(&restored, &a);
+        assert_eq!(restored.epoch(), 1);
+
+        // A delta from a later epoch needs the rebalance.
+        let mut e = random_replica(1);
+        let delta = a.delta_since(a.version());
+        assert_eq!(e.apply_delta(&delta).unwrap_err(), LseqError::EpochMismatch { expected: 0, found: 1 });
+        let mut old = random_replica(1).delta_since(&VersionVector::new());
+        assert_eq!(old.join(&delta).unwrap_err(), LseqError::EpochMismatch { expected: 0, found: 1 });
+        e.apply_delta(&a.delta_since(e.version())).unwrap();
+        assert_eq!(e.contents, a.contents);
+    }
+
+    #[test]
+    fn test_rebalance_conflict() {
+        // `a` and `b` rebalance concurrently, `b` twice, editing after each.
+        let rebalanced = |seed: u64, times: usize| {
+            let mut seq = random_replica(seed);
+            let mut rng = XorShiftRng::seed_from_u64(seed);
+            for i in 0..times {
+                seq.rebalance(i, seq.len() - 2 * i);
+                for j in 0..10 {
+                    random_edit(&mut seq, &mut rng, 1000 * seed as u32 + j);
+                }
+            }
+            let ops = seq.missing_ops_since(&VersionVector::new());
+            (seq, ops)
+        };
+        let (mut a, from_a) = rebalanced(1, 1);
+        let (mut b, from_b) = rebalanced(2, 2);
+
+        // Whatever order they arrive in, `a`'s rebalance wins since its op is
+        // less, and `b`'s second rebalance loses with the first.
+        let mut ab = random_replica(3);
+        for op in from_a.iter().chain(&from_b) {
+            ab.apply_remote(op.clone()).unwrap();
+        }
+        let mut ba = random_replica(3);
+        for op in from_b.iter().chain(&from_a) {
+            ba.apply_remote(op.clone()).unwrap();
+        }
+        assert_same(&ab, &ba);
+        assert_eq!(ba.epoch(), 1);
+        assert_eq!(ba.base(), a.base());
+        assert_eq!(ba.losers.len(), 2);
+        assert_ordered(&ba);
+
+        // So do merges and deltas.
+        let mut merged = random_replica(3);
+        merged.merge(&b).unwrap();
+        merged.merge(&a).unwrap();
+        assert_same(&merged, &ab);
+        let mut c = random_replica(3);
+        c.apply_delta(&b.delta_since(c.version())).unwrap();
+        c.apply_delta(&a.delta_since(c.version())).unwrap();
+        assert_eq!(c.contents, ab.contents);
+        assert_eq!(c.version(), ab.version());
+
+        // Ops waiting for the rebalance they're based on are merged too.
+        let is_rebalance = |op: &&Op<u32>| matches!(op.kind, OpKind::Rebalance(_));
+        let build = |seed, ops: Vec<&Op<u32>>| {
+            let mut seq = random_replica(seed);
+            for op in ops {
+                seq.apply_remote(op.clone()).unwrap();
+            }
+            seq
+        };
+        let waiting = || build(3, from_a.iter().filter(|op| !is_rebalance(op)).collect());
+        let rebalance = || build(4, from_a.iter().filter(is_rebalance).collect());
+        let mut wr = waiting();
+        wr.merge(&rebalance()).unwrap();
+        let mut rw = rebalance();
+        rw.merge(&waiting()).unwrap();
+        assert_same(&wr, &rw);
+
+        // `b` undoes its rebalances to converge with `a`.
+        for op in &from_b {
+            a.apply_remote(op.clone()).unwrap();
+        }
+        for op in &from_a {
+            b.apply_remote(op.clone()).unwrap();
+        }
+        assert_eq!(b.epoch(), 1);
+        assert_eq!(a.contents, b.contents);
+        assert_eq!(a.version(), b.version());
+        let restored: LSeq<u32> = bincode::deserialize(&bincode::serialize(&b).unwrap()).unwrap();
+        assert_same(&restored, &b);
+
+        // Rebalances can't be hidden in batches.
+        let mut batch = from_b.iter().find(|op| matches!(op.kind, OpKind::Rebalance(_))).unwrap().clone();
+        batch.kind = OpKind::Batch(vec![batch.kind]);
+        batch.seq = b.version().get(b.node_id()) + 1;
+        assert_eq!(c.apply_remote(batch).unwrap_err(), LseqError::InvalidState("rebalance in a batch"));
+    }
+}
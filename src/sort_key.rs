@@ -0,0 +1,223 @@
+//! Order preserving encodings of `Id`s, for storing ids in databases which
+//! order keys by comparing bytes.
+//!
+//! Each level is encoded as its index, then its node, then its counter, each
+//! using a variable length encoding in which larger numbers are never shorter
+//! than smaller ones, and no encoding is a prefix of another, so comparing
+//! keys byte by byte gives the same result as comparing the `Id`s.
+
+use crate::{Id, Level, LseqError, NodeId};
+use std::convert::TryFrom;
+
+// Numbers below this take one byte, larger numbers take a length byte
+// (`SHORT_LIMIT + len - 1`) followed by `len` big endian bytes.
+const SHORT_LIMIT: u8 = 0xf0;
+
+// Ascending in ASCII, so sort key strings compare the same as sort keys.
+const ALPHABET: &[u8; 64] = b"-0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz";
+
+impl Id {
+    /// Encode this id so that comparing the keys of two ids byte by byte
+    /// orders them the same way as `Ord`.
+    pub fn to_sort_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.levels.len() * 8);
+        for l in &self.levels {
+            write_number(&mut key, u128::from(l.index));
+            write_number(&mut key, l.node.0);
+            write_number(&mut key, u128::from(l.counter));
+        }
+        key
+    }
+
+    /// Decode a key created by `Id::to_sort_key`. Keys of ids which no `Node`
+    /// allocates, i.e., the empty id and ids whose last index is 0 (such as
+    /// `Node::begin`), are rejected, so every key is the key of one real id.
+    pub fn from_sort_key(key: &[u8]) -> Result<Id, LseqError> {
+        if key.is_empty() {
+            return Err(LseqError::InvalidEncoding("empty key"));
+        }
+        let mut rest = key;
+        let mut levels = Vec::new();
+        while !rest.is_empty() {
+            let index = read_u64(&mut rest)?;
+            let node = NodeId(read_number(&mut rest)?);
+            let counter = read_u64(&mut rest)?;
+            levels.push(Level { index, node, counter });
+        }
+        if levels.last().map(|l| l.index) == Some(0) {
+            return Err(LseqError::ZeroLastIndex);
+        }
+        Ok(Id { levels })
+    }
+
+    /// Like `to_sort_key`, but encoded with a URL safe base 64 alphabet which
+    /// preserves the order of the keys.
+    pub fn to_sort_string(&self) -> String {
+        let key = self.to_sort_key();
+        let mut result = String::with_capacity((key.len() * 4).div_ceil(3));
+        for chunk in key.chunks(3) {
+            let mut group = 0u32;
+            for (i, b) in chunk.iter().enumerate() {
+                group |= u32::from(*b) << (16 - 8 * i);
+            }
+            // 1, 2 or 3 bytes need 2, 3 or 4 characters.
+            for i in 0..=chunk.len() {
+                result.push(ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char);
+            }
+        }
+        result
+    }
+
+    /// Decode a string created by `Id::to_sort_string`.
+    pub fn from_sort_string(s: &str) -> Result<Id, LseqError> {
+        let digits = s
+            .bytes()
+            .map(|c| ALPHABET.iter().position(|a| *a == c).map(|d| d as u32))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(LseqError::InvalidEncoding("invalid character"))?;
+
+        let mut key = Vec::with_capacity(digits.len() * 3 / 4);
+        for chunk in digits.chunks(4) {
+            if chunk.len() == 1 {
+                return Err(LseqError::InvalidEncoding("invalid length"));
+            }
+            let mut group = 0u32;
+            for (i, d) in chunk.iter().enumerate() {
+                group |= d << (18 - 6 * i);
+            }
+            let bytes = chunk.len() - 1;
+            // The padding bits must be zero, otherwise two strings would
+            // decode to the same key.
+            if group & (0xff_ffff >> (8 * bytes)) != 0 {
+                return Err(LseqError::InvalidEncoding("non-zero padding"));
+            }
+            for i in 0..bytes {
+                key.push((group >> (16 - 8 * i)) as u8);
+            }
+        }
+        Id::from_sort_key(&key)
+    }
+}
+
+fn write_number(key: &mut Vec<u8>, n: u128) {
+    if n < u128::from(SHORT_LIMIT) {
+        key.push(n as u8);
+        return;
+    }
+    let len = 16 - n.leading_zeros() as usize / 8;
+    key.push(SHORT_LIMIT + (len as u8 - 1));
+    key.extend_from_slice(&n.to_be_bytes()[16 - len..]);
+}
+
+fn read_number(rest: &mut &[u8]) -> Result<u128, LseqError> {
+    let (first, tail) = rest.split_first().ok_or(LseqError::InvalidEncoding("unexpected end of input"))?;
+    if *first < SHORT_LIMIT {
+        *rest = tail;
+        return Ok(u128::from(*first));
+    }
+
+    let len = (first - SHORT_LIMIT) as usize + 1;
+    if tail.len() < len {
+        return Err(LseqError::InvalidEncoding("unexpected end of input"));
+    }
+    let (bytes, tail) = tail.split_at(len);
+    *rest = tail;
+    let n = bytes.iter().fold(0, |n, b| (n << 8) | u128::from(*b));
+    // Only the shortest encoding of each number is valid, so that every id has
+    // exactly one key.
+    if n < u128::from(SHORT_LIMIT) || bytes[0] == 0 {
+        return Err(LseqError::InvalidEncoding("non-canonical number"));
+    }
+    Ok(n)
+}
+
+fn read_u64(rest: &mut &[u8]) -> Result<u64, LseqError> {
+    u64::try_from(read_number(rest)?).map_err(|_| LseqError::InvalidEncoding("number out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{allocate_ids, id};
+    use crate::Node;
+
+    fn sample_ids() -> Vec<Id> {
+        let mut ids = vec![
+            id(&[(1, 0, 1)]),
+            id(&[(1, 0, 1), (1, 0, 0)]),
+            id(&[(1, 1, 1)]),
+            id(&[(1, 1, 2)]),
+            id(&[(1, 0x100, 1)]),
+            id(&[(1, u128::from(u32::MAX), 1)]),
+            id(&[(1, u128::from(u64::MAX), 1)]),
+            id(&[(1, u128::MAX, 1)]),
+            id(&[(0xef, 0, 1)]),
+            id(&[(0xf0, 0, 1)]),
+            id(&[(0xf7, 0, 1)]),
+            id(&[(0xf8, 0, 1)]),
+            id(&[(0xff, 0, 1)]),
+            id(&[(0x100, 0, 1)]),
+            id(&[(u64::MAX, 0, u64::MAX)]),
+            id(&[(2, 3, 0xf0), (5, 1, 1)]),
+            id(&[(2, 3, 0xef), (5, 1, 1)]),
+        ];
+
+        let mut a = Node::new(NodeId::new(1));
+        let mut b = Node::new(NodeId::random());
+        ids.extend(allocate_ids(&mut a, &mut b, 300));
+        ids
+    }
+
+    #[test]
+    fn test_number() {
+        for n in &[0, 1, 0xef, 0xf0, 0xff, 0x100, 0xffff, 0x1_0000, u128::from(u64::MAX), u128::MAX] {
+            let mut key = Vec::new();
+            write_number(&mut key, *n);
+            let mut rest = &key[..];
+            assert_eq!(read_number(&mut rest), Ok(*n));
+            assert!(rest.is_empty());
+        }
+
+        assert!(read_number(&mut &[0xf0, 0x05][..]).is_err());
+        assert!(read_number(&mut &[0xf1, 0x00, 0xff][..]).is_err());
+        assert!(read_number(&mut &[0xf1, 0x01][..]).is_err());
+        assert!(read_u64(&mut &[0xf8, 1, 0, 0, 0, 0, 0, 0, 0, 0][..]).is_err());
+    }
+
+    #[test]
+    fn test_sort_key_order() {
+        let ids = sample_ids();
+        for a in &ids {
+            for b in &ids {
+                assert_eq!(a.cmp(b), a.to_sort_key().cmp(&b.to_sort_key()), "{:?} {:?}", a, b);
+                assert_eq!(a.cmp(b), a.to_sort_string().cmp(&b.to_sort_string()), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_key_round_trip() {
+        for id in sample_ids() {
+            assert_eq!(Id::from_sort_key(&id.to_sort_key()), Ok(id.clone()));
+            assert_eq!(Id::from_sort_string(&id.to_sort_string()), Ok(id));
+        }
+    }
+
+    #[test]
+    fn test_sort_key_errors() {
+        let key = id(&[(1, 2, 3)]).to_sort_key();
+        assert!(Id::from_sort_key(&key[..key.len() - 1]).is_err());
+        assert!(Id::from_sort_key(&key[..1]).is_err());
+        assert!(Id::from_sort_key(&[1, 0xf1, 0x01]).is_err());
+        assert_eq!(Id::from_sort_key(&[]), Err(LseqError::InvalidEncoding("empty key")));
+        assert_eq!(Id::from_sort_string(""), Err(LseqError::InvalidEncoding("empty key")));
+        assert_eq!(Id::from_sort_key(&id(&[(1, 2, 3), (0, 2, 4)]).to_sort_key()), Err(LseqError::ZeroLastIndex));
+        assert_eq!(Id::from_sort_key(&Node::new(NodeId::new(1)).begin().to_sort_key()), Err(LseqError::ZeroLastIndex));
+
+        let s = id(&[(1, 2, 3)]).to_sort_string();
+        assert!(Id::from_sort_string(&s[..s.len() - 1]).is_err());
+        assert_eq!(Id::from_sort_string("0z"), Err(LseqError::InvalidEncoding("non-zero padding")));
+        assert_eq!(Id::from_sort_string("0"), Err(LseqError::InvalidEncoding("invalid length")));
+        assert_eq!(Id::from_sort_string("0+"), Err(LseqError::InvalidEncoding("invalid character")));
+    }
+}
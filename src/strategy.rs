@@ -0,0 +1,259 @@
+use crate::{mix64, random_bool, random_range, NodeConfig};
+use bit_vec::BitVec;
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+
+/// Decides where between two bounds a `Node` allocates a new index.
+///
+/// All bounds are exclusive and `lower_bound + 1 < upper_bound`; the returned
+/// index must be strictly between the two. Strategies should only use `rng`
+/// as a source of randomness so that seeded `Node`s are deterministic.
+pub trait AllocationStrategy {
+    fn pick_index(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, level: usize, lower_bound: u64, upper_bound: u64) -> u64;
+}
+
+/// Allocates close to the lower bound, leaving room after the new id. Good
+/// for data which is mostly appended to, such as logs.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BoundaryPlus;
+
+/// Allocates close to the upper bound, leaving room before the new id. Good
+/// for data which is mostly prepended to.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BoundaryMinus;
+
+/// The LSEQ strategy: each level randomly uses boundary+ or boundary-. The
+/// choice for each level is made once per `Node`, unless the config has a
+/// document seed, in which case the choice is derived from the seed and is
+/// the same for every `Node`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RandomMix {
+    // True = upper (boundary-), false = lower (boundary+).
+    #[serde(with = "bits")]
+    directions: BitVec,
+}
+
+/// Like `RandomMix`, but the choice for each level is a hash of the level (and
+/// the document seed, if there is one), so every `Node` makes the same choices
+/// (h-LSEQ).
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HashMix;
+
+impl AllocationStrategy for BoundaryPlus {
+    fn pick_index(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, _: usize, lower_bound: u64, upper_bound: u64) -> u64 {
+        pick_near_lower(config, rng, lower_bound, upper_bound)
+    }
+}
+
+impl AllocationStrategy for BoundaryMinus {
+    fn pick_index(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, _: usize, lower_bound: u64, upper_bound: u64) -> u64 {
+        pick_near_upper(config, rng, lower_bound, upper_bound)
+    }
+}
+
+impl RandomMix {
+    pub fn new() -> RandomMix {
+        RandomMix::default()
+    }
+
+    fn level_direction(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, level: usize) -> bool {
+        if let Some(seed) = config.document_seed() {
+            return shared_direction(seed, level);
+        }
+
+        while level >= self.directions.len() {
+            let result = random_bool(rng);
+            self.directions.push(result);
+        }
+
+        self.directions[level]
+    }
+}
+
+impl AllocationStrategy for RandomMix {
+    fn pick_index(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, level: usize, lower_bound: u64, upper_bound: u64) -> u64 {
+        if self.level_direction(config, rng, level) {
+            pick_near_upper(config, rng, lower_bound, upper_bound)
+        } else {
+            pick_near_lower(config, rng, lower_bound, upper_bound)
+        }
+    }
+}
+
+impl AllocationStrategy for HashMix {
+    fn pick_index(&mut self, config: &NodeConfig, rng: &mut dyn RngCore, level: usize, lower_bound: u64, upper_bound: u64) -> u64 {
+        if shared_direction(config.document_seed().unwrap_or(0), level) {
+            pick_near_upper(config, rng, lower_bound, upper_bound)
+        } else {
+            pick_near_lower(config, rng, lower_bound, upper_bound)
+        }
+    }
+}
+
+// bit-vec does not support serde.
+mod bits {
+    use bit_vec::BitVec;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bits: &BitVec, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(bits.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BitVec, D::Error> {
+        Ok(Vec::<bool>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+// True = upper (boundary-), false = lower (boundary+).
+fn shared_direction(seed: u64, level: usize) -> bool {
+    mix64(seed ^ mix64(level as u64)) & 1 == 1
+}
+
+// boundary+
+fn pick_near_lower(config: &NodeConfig, rng: &mut dyn RngCore, lower_bound: u64, upper_bound: u64) -> u64 {
+    assert!(lower_bound + 1 < upper_bound, "{} < {}", lower_bound + 1, upper_bound);
+    // Picks in (lower, lower + boundary), as LSEQ does, but always leaves
+    // room for `lower + 1` so that a boundary of 1 still works.
+    let mut boundary = lower_bound.saturating_add(config.boundary()).max(lower_bound + 2);
+    if boundary > upper_bound {
+        boundary = upper_bound;
+    }
+    random_range(rng, lower_bound, boundary)
+}
+
+// boundary-
+fn pick_near_upper(config: &NodeConfig, rng: &mut dyn RngCore, lower_bound: u64, upper_bound: u64) -> u64 {
+    assert!(lower_bound + 1 < upper_bound, "{} < {}", lower_bound + 1, upper_bound);
+    let mut boundary = upper_bound.saturating_sub(config.boundary().saturating_add(1));
+    if boundary < lower_bound {
+        boundary = lower_bound;
+    }
+    random_range(rng, boundary, upper_bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Node, NodeId};
+    use rand::thread_rng;
+
+    fn check_bounds<S: AllocationStrategy>(strategy: &mut S, config: &NodeConfig) {
+        for i in 0..20 {
+            for j in (i + 2)..20 {
+                for level in 0..10 {
+                    for _ in 0..100 {
+                        let new_index = strategy.pick_index(config, &mut thread_rng(), level, i, j);
+                        assert!(new_index > i && new_index < j);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pick_index_bounds() {
+        for boundary in &[1, 3, 10] {
+            let config = NodeConfig::builder().boundary(*boundary).build();
+            check_bounds(&mut BoundaryPlus, &config);
+            check_bounds(&mut BoundaryMinus, &config);
+            check_bounds(&mut RandomMix::new(), &config);
+            check_bounds(&mut HashMix, &config);
+        }
+    }
+
+    #[test]
+    fn test_boundary() {
+        let config = NodeConfig::builder().boundary(3).build();
+        for _ in 0..100 {
+            let plus = BoundaryPlus.pick_index(&config, &mut thread_rng(), 0, 10, 100);
+            assert!((11..13).contains(&plus));
+            let minus = BoundaryMinus.pick_index(&config, &mut thread_rng(), 0, 10, 100);
+            assert!((97..100).contains(&minus));
+        }
+    }
+
+    #[test]
+    fn test_boundary_one() {
+        let config = NodeConfig::builder().boundary(1).build();
+        for _ in 0..100 {
+            assert_eq!(BoundaryPlus.pick_index(&config, &mut thread_rng(), 0, 10, 100), 11);
+            assert_eq!(BoundaryMinus.pick_index(&config, &mut thread_rng(), 0, 10, 100), 99);
+        }
+    }
+
+    #[test]
+    fn test_huge_boundary() {
+        // The boundary is clamped to the bounds rather than overflowing.
+        let config = NodeConfig::builder().boundary(u64::MAX).build();
+        check_bounds(&mut BoundaryPlus, &config);
+        check_bounds(&mut BoundaryMinus, &config);
+        let mut node = Node::with_config(NodeId::new(1), config);
+        let first = node.new_id_between(None, None);
+        let second = node.new_id_after(&first);
+        assert!(first < second);
+    }
+
+    #[test]
+    fn test_level_direction() {
+        let mut strategy = RandomMix::new();
+        let config = &NodeConfig::new();
+        let rng = &mut thread_rng();
+        let a = strategy.level_direction(config, rng, 0);
+        let b = strategy.level_direction(config, rng, 1);
+        let c = strategy.level_direction(config, rng, 2);
+        let d = strategy.level_direction(config, rng, 3);
+
+        let a_ = strategy.level_direction(config, rng, 0);
+        let b_ = strategy.level_direction(config, rng, 1);
+        let c_ = strategy.level_direction(config, rng, 2);
+        let d_ = strategy.level_direction(config, rng, 3);
+
+        assert!(a == a_);
+        assert!(b == b_);
+        assert!(c == c_);
+        assert!(d == d_);
+
+        // Test that eventually we'll get both values.
+        let mut f = false;
+        let mut t = false;
+        let mut i = 0;
+        loop {
+            let a = strategy.level_direction(config, rng, i);
+            if a {
+                t = true;
+            } else {
+                f = true;
+            }
+
+            if t && f {
+                return;
+            }
+
+            i += 1;
+        }
+    }
+
+    #[test]
+    fn test_shared_direction() {
+        let directions = |seed| (0..64).map(|l| shared_direction(seed, l)).collect::<Vec<_>>();
+        let a = directions(0);
+        assert!(a.iter().any(|d| *d));
+        assert!(a.iter().any(|d| !*d));
+        assert_eq!(a, directions(0));
+        assert_ne!(a, directions(1));
+    }
+
+    #[test]
+    fn test_document_seed() {
+        let config = NodeConfig::builder().document_seed(42).build();
+        let rng = &mut thread_rng();
+        // Independent strategies agree on every level.
+        let mut a = RandomMix::new();
+        let mut b = RandomMix::new();
+        for level in 0..64 {
+            let direction = a.level_direction(&config, rng, level);
+            assert_eq!(direction, b.level_direction(&config, rng, level));
+            assert_eq!(direction, shared_direction(42, level));
+        }
+    }
+}
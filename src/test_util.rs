@@ -0,0 +1,23 @@
+// Fixtures shared by the tests of several modules.
+
+use crate::{Id, Level, Node, NodeId};
+
+// Makes an id from (index, node, counter) triples.
+pub(crate) fn id(levels: &[(u64, u128, u64)]) -> Id {
+    Id {
+        levels: levels.iter().map(|&(index, node, counter)| Level { index, node: NodeId(node), counter }).collect(),
+    }
+}
+
+// Allocates `n` ids in order, alternating between `a` and `b` and inserting
+// at scattered positions so that the ids have a mix of depths.
+pub(crate) fn allocate_ids(a: &mut Node, b: &mut Node, n: usize) -> Vec<Id> {
+    let mut ids: Vec<Id> = Vec::new();
+    for i in 0..n {
+        let pos = (i * 7919) % (ids.len() + 1);
+        let node = if i % 2 == 0 { &mut *a } else { &mut *b };
+        let id = node.new_id_between(pos.checked_sub(1).map(|p| &ids[p]), ids.get(pos));
+        ids.insert(pos, id);
+    }
+    ids
+}
@@ -0,0 +1,370 @@
+//! An order statistic tree: a balanced (AVL) binary search tree in which every
+//! node records the size of its subtree, so that elements can be found,
+//! inserted and removed by key or by position in O(log n).
+//!
+//! Each value may stand for several elements (e.g., a run of elements), the
+//! size of a subtree is the sum of the weights of its values and positions
+//! count elements rather than values.
+
+use std::cmp::Ordering;
+
+pub(crate) trait Weighted {
+    /// The number of elements this value stands for, must be at least 1.
+    fn weight(&self) -> usize;
+}
+
+#[derive(Clone)]
+pub(crate) struct OrderTree<K, V> {
+    root: Link<K, V>,
+}
+
+type Link<K, V> = Option<Box<TreeNode<K, V>>>;
+
+#[derive(Clone)]
+struct TreeNode<K, V> {
+    key: K,
+    value: V,
+    // Height and total weight of the subtree rooted at this node.
+    height: u32,
+    size: usize,
+    left: Link<K, V>,
+    right: Link<K, V>,
+}
+
+impl<K: Ord, V: Weighted> OrderTree<K, V> {
+    pub(crate) fn new() -> OrderTree<K, V> {
+        OrderTree { root: None }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        size(&self.root)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// The value with the greatest key less than or equal to `key`, and the
+    /// position of its first element.
+    pub(crate) fn floor(&self, key: &K) -> Option<(&K, &V, usize)> {
+        let mut link = &self.root;
+        let mut before = 0;
+        let mut result = None;
+        while let Some(node) = link {
+            match key.cmp(&node.key) {
+                Ordering::Less => link = &node.left,
+                Ordering::Equal => return Some((&node.key, &node.value, before + size(&node.left))),
+                Ordering::Greater => {
+                    result = Some((&node.key, &node.value, before + size(&node.left)));
+                    before += size(&node.left) + node.value.weight();
+                    link = &node.right;
+                }
+            }
+        }
+        result
+    }
+
+    /// The value with the least key greater than `key`.
+    pub(crate) fn next(&self, key: &K) -> Option<(&K, &V)> {
+        let mut link = &self.root;
+        let mut result = None;
+        while let Some(node) = link {
+            if *key < node.key {
+                result = Some((&node.key, &node.value));
+                link = &node.left;
+            } else {
+                link = &node.right;
+            }
+        }
+        result
+    }
+
+    /// The key and value containing the element at `index`, and the offset
+    /// of the element within the value.
+    pub(crate) fn get_index(&self, mut index: usize) -> Option<(&K, &V, usize)> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let left = size(&node.left);
+            if index < left {
+                link = &node.left;
+            } else if index < left + node.value.weight() {
+                return Some((&node.key, &node.value, index - left));
+            } else {
+                index -= left + node.value.weight();
+                link = &node.right;
+            }
+        }
+        None
+    }
+
+    /// Returns false, and leaves the tree unchanged, if `key` is already
+    /// present.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> bool {
+        let (root, inserted) = insert(self.root.take(), key, value);
+        self.root = Some(root);
+        inserted
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let (root, removed) = remove(self.root.take(), key);
+        self.root = root;
+        removed.map(|(_, value)| value)
+    }
+
+    /// Iterate over keys and values in key order.
+    pub(crate) fn iter(&self) -> Iter<'_, K, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+}
+
+pub(crate) struct Iter<'a, K, V> {
+    // The nodes whose left subtrees are being visited, the next node to yield
+    // is on top.
+    stack: Vec<&'a TreeNode<K, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn push_left(&mut self, mut link: &'a Link<K, V>) {
+        while let Some(node) = link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some((&node.key, &node.value))
+    }
+}
+
+fn size<K, V>(link: &Link<K, V>) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+fn height<K, V>(link: &Link<K, V>) -> u32 {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn insert<K: Ord, V: Weighted>(link: Link<K, V>, key: K, value: V) -> (Box<TreeNode<K, V>>, bool) {
+    let mut node = match link {
+        Some(node) => node,
+        None => {
+            let size = value.weight();
+            let leaf = TreeNode { key, value, height: 1, size, left: None, right: None };
+            return (Box::new(leaf), true);
+        }
+    };
+
+    let inserted = match key.cmp(&node.key) {
+        Ordering::Less => {
+            let (left, inserted) = insert(node.left.take(), key, value);
+            node.left = Some(left);
+            inserted
+        }
+        Ordering::Greater => {
+            let (right, inserted) = insert(node.right.take(), key, value);
+            node.right = Some(right);
+            inserted
+        }
+        Ordering::Equal => return (node, false),
+    };
+    (rebalance(node), inserted)
+}
+
+fn remove<K: Ord, V: Weighted>(link: Link<K, V>, key: &K) -> (Link<K, V>, Option<(K, V)>) {
+    let mut node = match link {
+        Some(node) => node,
+        None => return (None, None),
+    };
+
+    match key.cmp(&node.key) {
+        Ordering::Less => {
+            let (left, removed) = remove(node.left.take(), key);
+            node.left = left;
+            (Some(rebalance(node)), removed)
+        }
+        Ordering::Greater => {
+            let (right, removed) = remove(node.right.take(), key);
+            node.right = right;
+            (Some(rebalance(node)), removed)
+        }
+        Ordering::Equal => {
+            let TreeNode { key, value, left, right, .. } = *node;
+            let replacement = match (left, right) {
+                (None, right) => right,
+                (left, None) => left,
+                (left, Some(right)) => {
+                    let (right, mut min) = remove_min(right);
+                    min.left = left;
+                    min.right = right;
+                    Some(rebalance(min))
+                }
+            };
+            (replacement, Some((key, value)))
+        }
+    }
+}
+
+// Returns the remaining subtree and its minimum node.
+fn remove_min<K, V: Weighted>(mut node: Box<TreeNode<K, V>>) -> (Link<K, V>, Box<TreeNode<K, V>>) {
+    match node.left.take() {
+        Some(left) => {
+            let (left, min) = remove_min(left);
+            node.left = left;
+            (Some(rebalance(node)), min)
+        }
+        None => (node.right.take(), node),
+    }
+}
+
+fn update<K, V: Weighted>(node: &mut TreeNode<K, V>) {
+    node.height = 1 + height(&node.left).max(height(&node.right));
+    node.size = node.value.weight() + size(&node.left) + size(&node.right);
+}
+
+// Restore the AVL property at `node`, assuming its subtrees are balanced and
+// their heights differ by at most 2.
+fn rebalance<K, V: Weighted>(mut node: Box<TreeNode<K, V>>) -> Box<TreeNode<K, V>> {
+    update(&mut node);
+    let (left, right) = (height(&node.left), height(&node.right));
+    if left > right + 1 {
+        let child = node.left.take().unwrap();
+        node.left = Some(if height(&child.left) < height(&child.right) { rotate_left(child) } else { child });
+        rotate_right(node)
+    } else if right > left + 1 {
+        let child = node.right.take().unwrap();
+        node.right = Some(if height(&child.right) < height(&child.left) { rotate_right(child) } else { child });
+        rotate_left(node)
+    } else {
+        node
+    }
+}
+
+fn rotate_left<K, V: Weighted>(mut node: Box<TreeNode<K, V>>) -> Box<TreeNode<K, V>> {
+    let mut right = node.right.take().unwrap();
+    node.right = right.left.take();
+    update(&mut node);
+    right.left = Some(node);
+    update(&mut right);
+    right
+}
+
+fn rotate_right<K, V: Weighted>(mut node: Box<TreeNode<K, V>>) -> Box<TreeNode<K, V>> {
+    let mut left = node.left.take().unwrap();
+    node.left = left.right.take();
+    update(&mut node);
+    left.right = Some(node);
+    update(&mut left);
+    left
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    // A value standing for `k % 3 + 1` elements.
+    #[derive(Debug, PartialEq)]
+    struct Value(u32);
+
+    impl Weighted for Value {
+        fn weight(&self) -> usize {
+            self.0 as usize % 3 + 1
+        }
+    }
+
+    // Checks sizes, heights and balance, returns the height.
+    fn check<K: Ord>(link: &Link<K, Value>) -> u32 {
+        match link {
+            None => 0,
+            Some(node) => {
+                let left = check(&node.left);
+                let right = check(&node.right);
+                assert!(left <= right + 1 && right <= left + 1);
+                assert_eq!(node.height, 1 + left.max(right));
+                assert_eq!(node.size, node.value.weight() + size(&node.left) + size(&node.right));
+                node.height
+            }
+        }
+    }
+
+    // `model` is the sorted keys, the value for each key `k` is `Value(k)`.
+    fn assert_matches(tree: &OrderTree<u32, Value>, model: &[u32]) {
+        check(&tree.root);
+        assert_eq!(tree.iter().map(|(k, _)| *k).collect::<Vec<_>>(), model);
+        assert_eq!(tree.is_empty(), model.is_empty());
+
+        let mut index = 0;
+        for (i, k) in model.iter().enumerate() {
+            let value = Value(*k);
+            assert_eq!(tree.floor(k), Some((k, &value, index)));
+            assert_eq!(tree.next(k).map(|(k, _)| k), model.get(i + 1));
+            for offset in 0..value.weight() {
+                assert_eq!(tree.get_index(index + offset), Some((k, &value, offset)));
+            }
+            index += value.weight();
+        }
+        assert_eq!(tree.len(), index);
+        assert_eq!(tree.get_index(index), None);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut tree = OrderTree::new();
+        let mut model = Vec::new();
+        for k in 0..200 {
+            assert!(tree.insert(k * 2, Value(k * 2)));
+            model.push(k * 2);
+        }
+        assert_matches(&tree, &model);
+        // Logarithmic height.
+        assert!(tree.root.as_ref().unwrap().height <= 11);
+
+        assert!(!tree.insert(10, Value(0)));
+        assert_eq!(tree.floor(&10).map(|(_, v, _)| v), Some(&Value(10)));
+        assert_eq!(tree.floor(&11).map(|(k, _, _)| *k), Some(10));
+        assert_eq!(tree.next(&11).map(|(k, _)| *k), Some(12));
+
+        for k in (0..200).rev().step_by(3) {
+            assert_eq!(tree.remove(&(k * 2)), Some(Value(k * 2)));
+            model.retain(|m| *m != k * 2);
+        }
+        assert_matches(&tree, &model);
+        assert_eq!(tree.remove(&1000), None);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut rng = thread_rng();
+        let mut tree = OrderTree::new();
+        let mut model: Vec<u32> = Vec::new();
+        for _ in 0..2000 {
+            let k = rng.gen_range(0, 1000);
+            let expected = model.binary_search(&k);
+            if rng.gen() {
+                assert_eq!(tree.insert(k, Value(k)), expected.is_err());
+                if let Err(i) = expected {
+                    model.insert(i, k);
+                }
+            } else {
+                let expected = expected.ok().map(|i| Value(model.remove(i)));
+                assert_eq!(tree.remove(&k), expected);
+            }
+
+            let floor = match model.binary_search(&k) {
+                Ok(i) => Some(model[i]),
+                Err(i) => i.checked_sub(1).map(|i| model[i]),
+            };
+            assert_eq!(tree.floor(&k).map(|(k, _, _)| *k), floor);
+            check(&tree.root);
+        }
+        assert_matches(&tree, &model);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::NodeId;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Records which ops a replica has seen: for each node, the number of ops
+/// created by that node, counting from its first op, which have all been
+/// seen. Ops are numbered from 1 (see `Op::seq`), so an op has been seen if
+/// its number is at most the node's entry.
+///
+/// Version vectors are partially ordered, `a < b` if every op seen by `a` has
+/// also been seen by `b`, and `b` has seen some op which `a` has not.
+///
+/// Replicas can reconcile by exchanging version vectors: each sends the other
+/// `LSeq::missing_ops_since` (or `LSeq::delta_since`) the other's version.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct VersionVector {
+    // Nodes with no seen ops are omitted, so that equal vectors compare equal.
+    counters: BTreeMap<NodeId, u64>,
+}
+
+impl VersionVector {
+    pub fn new() -> VersionVector {
+        VersionVector::default()
+    }
+
+    /// The number of consecutive ops from `node` which have been seen.
+    pub fn get(&self, node: NodeId) -> u64 {
+        self.counters.get(&node).cloned().unwrap_or(0)
+    }
+
+    /// Whether op number `seq` from `node` has been seen.
+    pub fn contains(&self, node: NodeId, seq: u64) -> bool {
+        seq <= self.get(node)
+    }
+
+    /// Record that all ops from `node` up to and including `seq` have been
+    /// seen. Never moves an entry backwards.
+    pub fn observe(&mut self, node: NodeId, seq: u64) {
+        if seq > 0 {
+            let counter = self.counters.entry(node).or_insert(0);
+            *counter = (*counter).max(seq);
+        }
+    }
+
+    /// Record everything seen by `other` as seen, i.e., the entry-wise
+    /// maximum.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (node, seq) in other.iter() {
+            self.observe(node, seq);
+        }
+    }
+
+    /// The nodes with at least one seen op, and their entries, in node order.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, u64)> + '_ {
+        self.counters.iter().map(|(node, seq)| (*node, *seq))
+    }
+}
+
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &VersionVector) -> Option<Ordering> {
+        let le = self.iter().all(|(node, seq)| seq <= other.get(node));
+        let ge = other.iter().all(|(node, seq)| seq <= self.get(node));
+        match (le, ge) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+/// An op, identified by its origin and seq.
+pub(crate) type Dot = (NodeId, u64);
+
+/// A set of ops, unlike a `VersionVector` it can have gaps. Stored as
+/// ranges of seqs for each origin.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize)]
+pub(crate) struct Dots {
+    // Sorted, non-empty, inclusive ranges which neither overlap nor touch.
+    ranges: BTreeMap<NodeId, Vec<(u64, u64)>>,
+}
+
+impl Dots {
+    pub(crate) fn new() -> Dots {
+        Dots::default()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub(crate) fn contains(&self, (origin, seq): Dot) -> bool {
+        self.ranges
+            .get(&origin)
+            .is_some_and(|ranges| ranges.iter().any(|&(first, last)| first <= seq && seq <= last))
+    }
+
+    pub(crate) fn insert(&mut self, (origin, seq): Dot) {
+        self.insert_range(origin, seq, seq);
+    }
+
+    fn insert_range(&mut self, origin: NodeId, first: u64, last: u64) {
+        debug_assert!(first > 0 && first <= last);
+        let ranges = self.ranges.entry(origin).or_default();
+        ranges.push((first, last));
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for &(first, last) in ranges.iter() {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+        *ranges = merged;
+    }
+
+    pub(crate) fn join(&mut self, other: &Dots) {
+        for (origin, ranges) in &other.ranges {
+            for &(first, last) in ranges {
+                self.insert_range(*origin, first, last);
+            }
+        }
+    }
+
+    /// The ops which are not in `version`.
+    pub(crate) fn since(&self, version: &VersionVector) -> Dots {
+        let mut result = Dots::new();
+        for (origin, ranges) in &self.ranges {
+            let seen = version.get(*origin);
+            for &(first, last) in ranges {
+                if last > seen {
+                    result.insert_range(*origin, first.max(seen + 1), last);
+                }
+            }
+        }
+        result
+    }
+
+    /// The number of consecutive ops from `origin`, see `VersionVector::get`.
+    pub(crate) fn prefix(&self, origin: NodeId) -> u64 {
+        match self.ranges.get(&origin).and_then(|ranges| ranges.first()) {
+            Some(&(1, last)) => last,
+            _ => 0,
+        }
+    }
+
+    /// The ops with no gaps before them.
+    pub(crate) fn version(&self) -> VersionVector {
+        let mut result = VersionVector::new();
+        for origin in self.ranges.keys() {
+            result.observe(*origin, self.prefix(*origin));
+        }
+        result
+    }
+}
+
+impl<'de> Deserialize<'de> for Dots {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Dots, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Dots")]
+        struct Fields {
+            ranges: BTreeMap<NodeId, Vec<(u64, u64)>>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        let mut dots = Dots::new();
+        for (origin, ranges) in f.ranges {
+            for (first, last) in ranges {
+                if first == 0 || first > last {
+                    return Err(de::Error::custom("invalid range of ops"));
+                }
+                dots.insert_range(origin, first, last);
+            }
+        }
+        Ok(dots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vv(entries: &[(u32, u64)]) -> VersionVector {
+        let mut result = VersionVector::new();
+        for &(node, seq) in entries {
+            result.observe(NodeId::from(node), seq);
+        }
+        result
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut v = vv(&[(1, 3)]);
+        assert_eq!(v.get(NodeId::from(1u32)), 3);
+        assert_eq!(v.get(NodeId::from(2u32)), 0);
+        assert!(v.contains(NodeId::from(1u32), 3));
+        assert!(!v.contains(NodeId::from(1u32), 4));
+
+        v.observe(NodeId::from(1u32), 2);
+        assert_eq!(v.get(NodeId::from(1u32)), 3);
+        v.observe(NodeId::from(2u32), 0);
+        assert_eq!(v, vv(&[(1, 3)]));
+        assert_eq!(v.iter().collect::<Vec<_>>(), vec![(NodeId::from(1u32), 3)]);
+    }
+
+    #[test]
+    fn test_order() {
+        assert_eq!(vv(&[]), VersionVector::new());
+        assert!(vv(&[]) < vv(&[(1, 1)]));
+        assert!(vv(&[(1, 1)]) < vv(&[(1, 2)]));
+        assert!(vv(&[(1, 2)]) < vv(&[(1, 2), (2, 1)]));
+        assert!(vv(&[(1, 2), (2, 1)]) > vv(&[(2, 1)]));
+        assert_eq!(vv(&[(1, 2)]).partial_cmp(&vv(&[(2, 1)])), None);
+        assert_eq!(vv(&[(1, 2), (2, 1)]).partial_cmp(&vv(&[(1, 1), (2, 2)])), None);
+    }
+
+    #[test]
+    fn test_dots() {
+        let node = NodeId::from(1u32);
+        let mut dots = Dots::new();
+        assert!(dots.is_empty());
+        for seq in &[5, 1, 3, 2, 7, 8] {
+            dots.insert((node, *seq));
+        }
+        assert_eq!(dots.ranges[&node], vec![(1, 3), (5, 5), (7, 8)]);
+        assert!(dots.contains((node, 2)));
+        assert!(!dots.contains((node, 4)));
+        assert!(!dots.contains((NodeId::from(2u32), 2)));
+        assert_eq!(dots.version(), vv(&[(1, 3)]));
+
+        let since = dots.since(&vv(&[(1, 5)]));
+        assert_eq!(since.ranges[&node], vec![(7, 8)]);
+        assert!(dots.since(&vv(&[(1, 8)])).is_empty());
+
+        let mut other = Dots::new();
+        other.insert((node, 4));
+        other.insert((NodeId::from(2u32), 1));
+        dots.join(&other);
+        assert_eq!(dots.ranges[&node], vec![(1, 5), (7, 8)]);
+        assert_eq!(dots.version(), vv(&[(1, 5), (2, 1)]));
+
+        let bytes = bincode::serialize(&dots).unwrap();
+        assert_eq!(bincode::deserialize::<Dots>(&bytes).unwrap(), dots);
+        let mut invalid = Dots::new();
+        invalid.ranges.insert(node, vec![(3, 2)]);
+        let bytes = bincode::serialize(&invalid).unwrap();
+        assert!(bincode::deserialize::<Dots>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = vv(&[(1, 2), (2, 5)]);
+        let b = vv(&[(1, 4), (3, 1)]);
+        a.merge(&b);
+        assert_eq!(a, vv(&[(1, 4), (2, 5), (3, 1)]));
+        assert!(a > b);
+        a.merge(&b);
+        assert_eq!(a, vv(&[(1, 4), (2, 5), (3, 1)]));
+    }
+}